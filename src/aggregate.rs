@@ -0,0 +1,508 @@
+use bytes::Bytes;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::point::{FieldValue, Point};
+use crate::shared::lock;
+use crate::sketch::Sketch;
+
+/// A summary of a field's values over a window, emitted as `<field>_<name>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Mean,
+    Min,
+    Max,
+    Sum,
+    Count,
+    First,
+    Last,
+    /// A quantile, named like `p99` or `p99.9`, estimated with a sketch.
+    Percentile(f64),
+}
+
+impl Aggregate {
+    pub fn parse(name: &str) -> Result<Aggregate, String> {
+        Ok(match name {
+            "mean" => Aggregate::Mean,
+            "min" => Aggregate::Min,
+            "max" => Aggregate::Max,
+            "sum" => Aggregate::Sum,
+            "count" => Aggregate::Count,
+            "first" => Aggregate::First,
+            "last" => Aggregate::Last,
+            _ => match name.strip_prefix('p').and_then(|p| p.parse::<f64>().ok()) {
+                Some(percent) if (0.0..=100.0).contains(&percent) => Aggregate::Percentile(percent),
+                _ => return Err(format!("unknown aggregate '{}'", name)),
+            },
+        })
+    }
+
+    fn name(self) -> String {
+        match self {
+            Aggregate::Mean => String::from("mean"),
+            Aggregate::Min => String::from("min"),
+            Aggregate::Max => String::from("max"),
+            Aggregate::Sum => String::from("sum"),
+            Aggregate::Count => String::from("count"),
+            Aggregate::First => String::from("first"),
+            Aggregate::Last => String::from("last"),
+            Aggregate::Percentile(percent) => format!("p{}", percent),
+        }
+    }
+}
+
+fn as_f64(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::Float(f) => Some(*f),
+        FieldValue::Integer(i) => Some(*i as f64),
+        FieldValue::UInteger(u) => Some(*u as f64),
+        FieldValue::String(_) | FieldValue::Boolean(_) => None,
+    }
+}
+
+/// What's been seen of one field in one window.
+struct FieldStats {
+    count: i64,
+    /// Count, sum and extremes of the numeric values only.
+    numeric: i64,
+    sum: f64,
+    /// Exact sums while every value has had the same integer type.
+    integer_sum: Option<i128>,
+    unsigned_sum: Option<u128>,
+    min: Option<(f64, FieldValue)>,
+    max: Option<(f64, FieldValue)>,
+    /// The values with the earliest and latest timestamps, with those.
+    first: (i64, FieldValue),
+    last: (i64, FieldValue),
+    /// Only kept when percentiles are wanted.
+    sketch: Option<Sketch>,
+    /// How many values were at most each histogram bound.
+    buckets: Vec<u64>,
+}
+
+impl FieldStats {
+    fn new(value: &FieldValue, timestamp: i64, percentiles: bool, buckets: usize) -> FieldStats {
+        FieldStats {
+            count: 0,
+            numeric: 0,
+            sum: 0.0,
+            integer_sum: Some(0),
+            unsigned_sum: Some(0),
+            min: None,
+            max: None,
+            first: (timestamp, value.clone()),
+            last: (timestamp, value.clone()),
+            sketch: if percentiles {
+                Some(Sketch::default())
+            } else {
+                None
+            },
+            buckets: vec![0; buckets],
+        }
+    }
+
+    fn add(&mut self, value: &FieldValue, timestamp: i64, bounds: &[f64]) {
+        self.count += 1;
+        if timestamp < self.first.0 {
+            self.first = (timestamp, value.clone());
+        }
+        if timestamp >= self.last.0 {
+            self.last = (timestamp, value.clone());
+        }
+        self.integer_sum = match value {
+            FieldValue::Integer(i) => self.integer_sum.map(|sum| sum + i128::from(*i)),
+            _ => None,
+        };
+        self.unsigned_sum = match value {
+            FieldValue::UInteger(u) => self.unsigned_sum.map(|sum| sum + u128::from(*u)),
+            _ => None,
+        };
+        let number = match as_f64(value) {
+            Some(number) => number,
+            None => return,
+        };
+        self.numeric += 1;
+        self.sum += number;
+        if let Some(sketch) = &mut self.sketch {
+            sketch.add(number);
+        }
+        for (bucket, bound) in self.buckets.iter_mut().zip(bounds) {
+            if number <= *bound {
+                *bucket += 1;
+            }
+        }
+        if self.min.as_ref().is_none_or(|(min, _)| number < *min) {
+            self.min = Some((number, value.clone()));
+        }
+        if self.max.as_ref().is_none_or(|(max, _)| number > *max) {
+            self.max = Some((number, value.clone()));
+        }
+    }
+
+    /// The aggregate's value, if it applies to the values seen and the
+    /// sum or mean didn't overflow.
+    fn value(&self, aggregate: Aggregate) -> Option<FieldValue> {
+        let value = match aggregate {
+            Aggregate::Count => Some(FieldValue::Integer(self.count)),
+            Aggregate::First => Some(self.first.1.clone()),
+            Aggregate::Last => Some(self.last.1.clone()),
+            _ if self.numeric < self.count => None,
+            Aggregate::Mean => Some(FieldValue::Float(self.sum / self.numeric as f64)),
+            Aggregate::Percentile(percent) => self
+                .sketch
+                .as_ref()?
+                .quantile(percent / 100.0)
+                .map(FieldValue::Float),
+            Aggregate::Min => self.min.as_ref().map(|(_, value)| value.clone()),
+            Aggregate::Max => self.max.as_ref().map(|(_, value)| value.clone()),
+            Aggregate::Sum => Some(match (self.integer_sum, self.unsigned_sum) {
+                (Some(sum), _) if sum >= i128::from(i64::MIN) && sum <= i128::from(i64::MAX) => {
+                    FieldValue::Integer(sum as i64)
+                }
+                (_, Some(sum)) if sum <= u128::from(u64::MAX) => FieldValue::UInteger(sum as u64),
+                _ => FieldValue::Float(self.sum),
+            }),
+        };
+        // Line protocol has no infinities or NaN.
+        match value {
+            Some(FieldValue::Float(f)) if !f.is_finite() => None,
+            value => value,
+        }
+    }
+}
+
+/// One series in one window.
+struct Window {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    start: i64,
+    fields: Vec<(String, FieldStats)>,
+}
+
+/// The open windows, and how far they've been flushed.
+struct Windows {
+    open: HashMap<(Bytes, i64), Window>,
+    /// Windows ending at or before this have been flushed.
+    closed_until: i64,
+    /// Points dropped since the last flush for coming after their window.
+    late: u64,
+}
+
+/// Which aggregates to compute for which fields.
+#[derive(Default)]
+pub struct AggregateSpec {
+    pub fields: HashMap<String, Vec<Aggregate>>,
+    /// For fields not listed in `fields`; those are ignored if it's empty.
+    pub default: Vec<Aggregate>,
+    /// Histogram bucket upper bounds by field, in ascending order.
+    pub histograms: HashMap<String, Vec<f64>>,
+}
+
+impl AggregateSpec {
+    fn aggregates(&self, field: &str) -> &[Aggregate] {
+        self.fields.get(field).unwrap_or(&self.default)
+    }
+
+    fn bounds(&self, field: &str) -> &[f64] {
+        self.histograms.get(field).map_or(&[], |b| b.as_slice())
+    }
+}
+
+/// Groups points by series and time window, and emits one point per series
+/// and window once the window has closed. The emitted point is stamped with
+/// the start of its window. Points for a window that was already emitted
+/// are dropped.
+pub struct Aggregator {
+    spec: AggregateSpec,
+    /// Window length in nanoseconds.
+    window: i64,
+    /// How long after its end a window still takes late points.
+    grace: i64,
+    max_windows: usize,
+    windows: Mutex<Windows>,
+}
+
+impl Aggregator {
+    pub fn new(spec: AggregateSpec, window: i64, grace: i64, max_windows: usize) -> Aggregator {
+        Aggregator {
+            spec,
+            window: window.max(1),
+            grace,
+            max_windows,
+            windows: Mutex::new(Windows {
+                open: HashMap::new(),
+                closed_until: i64::MIN,
+                late: 0,
+            }),
+        }
+    }
+
+    /// Adds the point to its window. `now` stamps points without a
+    /// timestamp. Returns false if its window was already flushed, or if
+    /// there's no room for a new window.
+    pub fn add(&self, point: &Point, now: i64) -> bool {
+        let timestamp = point.timestamp.unwrap_or(now);
+        let start = timestamp - timestamp.rem_euclid(self.window);
+        let mut windows = lock(&self.windows);
+        if start.saturating_add(self.window) <= windows.closed_until {
+            windows.late += 1;
+            return false;
+        }
+        let key = (point.series_key(), start);
+        if !windows.open.contains_key(&key) && windows.open.len() >= self.max_windows {
+            warn!(
+                "Aggregation window limit of {} reached, dropping points of {}",
+                self.max_windows, point.measurement
+            );
+            return false;
+        }
+        let window = windows.open.entry(key).or_insert_with(|| Window {
+            measurement: point.measurement.clone(),
+            tags: point.tags.clone(),
+            start,
+            fields: Vec::new(),
+        });
+        for (name, value) in &point.fields {
+            let aggregates = self.spec.aggregates(name);
+            let bounds = self.spec.bounds(name);
+            if aggregates.is_empty() && bounds.is_empty() {
+                continue;
+            }
+            match window.fields.iter_mut().find(|(n, _)| n == name) {
+                Some((_, stats)) => stats.add(value, timestamp, bounds),
+                None => {
+                    let percentiles = aggregates
+                        .iter()
+                        .any(|a| matches!(a, Aggregate::Percentile(_)));
+                    let mut stats = FieldStats::new(value, timestamp, percentiles, bounds.len());
+                    stats.add(value, timestamp, bounds);
+                    window.fields.push((name.clone(), stats));
+                }
+            }
+        }
+        true
+    }
+
+    /// Takes the windows that closed by `now`, as points.
+    pub fn flush(&self, now: i64) -> Vec<Point> {
+        let mut closed = Vec::new();
+        let closed_until = now.saturating_sub(self.grace);
+        let mut windows = lock(&self.windows);
+        windows.open.retain(|_, window| {
+            if window.start.saturating_add(self.window) > closed_until {
+                return true;
+            }
+            closed.push(self.emit(window));
+            false
+        });
+        windows.closed_until = windows.closed_until.max(closed_until);
+        if windows.late > 0 {
+            warn!(
+                "Dropped {} points that came after their aggregation window closed",
+                windows.late
+            );
+            windows.late = 0;
+        }
+        drop(windows);
+        let mut points: Vec<Point> = closed.into_iter().flatten().collect();
+        points.sort_by_key(|point| point.timestamp);
+        points
+    }
+
+    /// The window's aggregates as one point, followed by a point per
+    /// histogram bucket tagged with its upper bound as `le`.
+    fn emit(&self, window: &Window) -> Vec<Point> {
+        let point = |tags: Vec<(String, String)>, fields| Point {
+            measurement: window.measurement.clone(),
+            tags,
+            fields,
+            timestamp: Some(window.start),
+        };
+        let mut points = Vec::new();
+        let mut fields = Vec::new();
+        for (name, stats) in &window.fields {
+            for &aggregate in self.spec.aggregates(name) {
+                if let Some(value) = stats.value(aggregate) {
+                    fields.push((format!("{}_{}", name, aggregate.name()), value));
+                }
+            }
+        }
+        if !fields.is_empty() {
+            points.push(point(window.tags.clone(), fields));
+        }
+        for (name, stats) in &window.fields {
+            let bounds = self.spec.bounds(name);
+            if bounds.is_empty() || stats.numeric == 0 {
+                continue;
+            }
+            let counts = stats
+                .buckets
+                .iter()
+                .zip(bounds.iter().map(|b| b.to_string()));
+            for (count, le) in counts.chain(vec![(&(stats.numeric as u64), String::from("+Inf"))]) {
+                let mut tags = window.tags.clone();
+                tags.push((String::from("le"), le));
+                tags.sort_by(|a, b| a.0.cmp(&b.0));
+                let field = (
+                    format!("{}_bucket", name),
+                    FieldValue::Integer(*count as i64),
+                );
+                points.push(point(tags, vec![field]));
+            }
+        }
+        points
+    }
+}
+
+#[test]
+fn check_windowed_aggregates() {
+    use crate::point::test_point;
+
+    let mut fields = HashMap::new();
+    fields.insert(
+        String::from("duration"),
+        vec![Aggregate::Mean, Aggregate::Min, Aggregate::Max],
+    );
+    fields.insert(String::from("status"), vec![Aggregate::Last]);
+    let spec = AggregateSpec {
+        fields,
+        default: vec![Aggregate::Sum, Aggregate::Count],
+        ..AggregateSpec::default()
+    };
+    let aggregator = Aggregator::new(spec, 10, 5, 100);
+    let lines: &[&[u8]] = &[
+        b"http,host=a duration=2i,bytes=10u,status=\"ok\" 101",
+        b"http,host=a duration=6i,bytes=20u,status=\"slow\" 109",
+        b"http,host=b duration=1.5,bytes=5u 105",
+        b"http,host=a duration=3i,bytes=1u 110",
+    ];
+    for line in lines {
+        let point = test_point(line);
+        assert!(aggregator.add(&point, 0));
+    }
+    assert!(aggregator.flush(114).is_empty());
+    let lines: Vec<String> = aggregator
+        .flush(115)
+        .iter()
+        .map(|point| String::from_utf8(point.to_line().to_vec()).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.contains(&String::from(
+        "http,host=a duration_mean=4,duration_min=2i,duration_max=6i,bytes_sum=30u,bytes_count=2i,status_last=\"slow\" 100\n"
+    )));
+    assert!(lines.contains(&String::from(
+        "http,host=b duration_mean=1.5,duration_min=1.5,duration_max=1.5,bytes_sum=5u,bytes_count=1i 100\n"
+    )));
+    // Too late for the window that was flushed, in time for the next.
+    let late = test_point(b"http,host=a duration=1i 109");
+    assert!(!aggregator.add(&late, 0));
+    let next = test_point(b"http,host=a duration=1i 111");
+    assert!(aggregator.add(&next, 0));
+    assert_eq!(aggregator.flush(125).len(), 1);
+    assert!(aggregator.flush(1000).is_empty());
+}
+
+#[test]
+fn check_overflowing_aggregates_are_left_out() {
+    use crate::point::test_point;
+
+    let spec = AggregateSpec {
+        default: vec![Aggregate::Sum, Aggregate::Mean, Aggregate::Max],
+        ..AggregateSpec::default()
+    };
+    let aggregator = Aggregator::new(spec, 10, 0, 100);
+    assert!(aggregator.add(&test_point(b"m v=1e308 1"), 0));
+    assert!(aggregator.add(&test_point(b"m v=1e308 2"), 0));
+    let points = aggregator.flush(10);
+    assert_eq!(
+        points[0].fields,
+        vec![(String::from("v_max"), FieldValue::Float(1e308))]
+    );
+}
+
+#[test]
+fn check_first_and_last_follow_timestamps() {
+    use crate::point::test_point;
+
+    let spec = AggregateSpec {
+        default: vec![Aggregate::First, Aggregate::Last],
+        ..AggregateSpec::default()
+    };
+    let aggregator = Aggregator::new(spec, 10, 0, 100);
+    for line in &[&b"m v=2i 5"[..], b"m v=1i 1", b"m v=4i 9", b"m v=3i 7"] {
+        let point = test_point(line);
+        assert!(aggregator.add(&point, 0));
+    }
+    let points = aggregator.flush(10);
+    assert_eq!(&points[0].to_line()[..], &b"m v_first=1i,v_last=4i 0\n"[..]);
+}
+
+#[test]
+fn check_window_limit() {
+    use crate::point::test_point;
+
+    let spec = AggregateSpec {
+        default: vec![Aggregate::Count],
+        ..AggregateSpec::default()
+    };
+    let aggregator = Aggregator::new(spec, 10, 0, 1);
+    let a = test_point(b"m,s=a v=1 1");
+    let b = test_point(b"m,s=b v=1 1");
+    assert!(aggregator.add(&a, 0));
+    assert!(aggregator.add(&a, 0));
+    assert!(!aggregator.add(&b, 0));
+}
+
+#[test]
+fn check_percentiles_and_histograms() {
+    use crate::point::test_point;
+
+    assert_eq!(Aggregate::parse("p99.9"), Ok(Aggregate::Percentile(99.9)));
+    assert!(Aggregate::parse("p101").is_err());
+    assert!(Aggregate::parse("pmax").is_err());
+
+    let mut fields = HashMap::new();
+    fields.insert(
+        String::from("latency"),
+        vec![
+            Aggregate::parse("p50").unwrap(),
+            Aggregate::parse("p99").unwrap(),
+        ],
+    );
+    let mut histograms = HashMap::new();
+    histograms.insert(String::from("latency"), vec![10.0, 100.0]);
+    let spec = AggregateSpec {
+        fields,
+        histograms,
+        ..AggregateSpec::default()
+    };
+    let aggregator = Aggregator::new(spec, 1000, 0, 100);
+    for i in 1..=200 {
+        let line = format!("rpc,z=1 latency={}i 5", i);
+        let point = test_point(&line);
+        aggregator.add(&point, 0);
+    }
+    let points = aggregator.flush(1000);
+    assert_eq!(points.len(), 4);
+    match &points[0].fields[..] {
+        [(p50, FieldValue::Float(median)), (p99, FieldValue::Float(tail))] => {
+            assert_eq!((p50.as_str(), p99.as_str()), ("latency_p50", "latency_p99"));
+            assert!((median - 100.0).abs() <= 2.0, "p50 {}", median);
+            assert!((tail - 198.0).abs() <= 3.0, "p99 {}", tail);
+        }
+        fields => panic!("unexpected fields {:?}", fields),
+    }
+    let buckets: Vec<String> = points[1..]
+        .iter()
+        .map(|point| String::from_utf8(point.to_line().to_vec()).unwrap())
+        .collect();
+    assert_eq!(
+        buckets,
+        vec![
+            "rpc,le=10,z=1 latency_bucket=10i 0\n",
+            "rpc,le=100,z=1 latency_bucket=100i 0\n",
+            "rpc,le=+Inf,z=1 latency_bucket=200i 0\n",
+        ]
+    );
+}
@@ -0,0 +1,96 @@
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::shared::lock;
+
+/// The values of one tag key of one measurement seen within the window.
+#[derive(Default)]
+struct Values {
+    last_seen: HashMap<String, Instant>,
+    /// When the longest unseen value was last seen, so expired values are
+    /// only looked for once there are some.
+    oldest: Option<Instant>,
+    overflowing: bool,
+}
+
+impl Values {
+    fn evict(&mut self, now: Instant, window: Duration) {
+        if self
+            .oldest
+            .is_none_or(|oldest| now.duration_since(oldest) < window)
+        {
+            return;
+        }
+        self.last_seen
+            .retain(|_, seen| now.duration_since(*seen) < window);
+        self.oldest = self.last_seen.values().min().cloned();
+    }
+}
+
+/// Counts the distinct values of each (measurement, tag key) seen over a
+/// sliding window, and refuses new ones once there are `limit` of them.
+/// Memory stays bounded at `limit` values per key.
+pub struct CardinalityLimiter {
+    limit: usize,
+    window: Duration,
+    keys: Mutex<HashMap<(String, String), Values>>,
+}
+
+impl CardinalityLimiter {
+    pub fn new(limit: usize, window: Duration) -> CardinalityLimiter {
+        CardinalityLimiter {
+            limit,
+            window,
+            keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the value and returns true, unless it's new and the key is
+    /// already at its limit.
+    pub fn admit(&self, measurement: &str, key: &str, value: &str, now: Instant) -> bool {
+        let mut keys = lock(&self.keys);
+        let values = keys
+            .entry((measurement.to_string(), key.to_string()))
+            .or_default();
+        if let Some(seen) = values.last_seen.get_mut(value) {
+            *seen = now;
+            return true;
+        }
+        if values.last_seen.len() >= self.limit {
+            values.evict(now, self.window);
+        }
+        if values.last_seen.len() >= self.limit {
+            if !values.overflowing {
+                warn!(
+                    "Cardinality limit of {} values reached for tag {} of {}",
+                    self.limit, key, measurement
+                );
+                values.overflowing = true;
+            }
+            return false;
+        }
+        values.overflowing = false;
+        values.oldest.get_or_insert(now);
+        values.last_seen.insert(value.to_string(), now);
+        true
+    }
+}
+
+#[test]
+fn check_limit_over_sliding_window() {
+    let limiter = CardinalityLimiter::new(2, Duration::from_secs(60));
+    let start = Instant::now();
+    let at = |secs| start + Duration::from_secs(secs);
+    assert!(limiter.admit("http", "path", "/a", at(0)));
+    assert!(limiter.admit("http", "path", "/b", at(10)));
+    assert!(!limiter.admit("http", "path", "/c", at(20)));
+    assert!(limiter.admit("http", "path", "/a", at(30)));
+    assert!(limiter.admit("http", "host", "/c", at(30)));
+    assert!(limiter.admit("other", "path", "/c", at(30)));
+    // "/b" was last seen 60s ago, "/a" only 40s ago.
+    assert!(limiter.admit("http", "path", "/c", at(70)));
+    assert!(!limiter.admit("http", "path", "/d", at(80)));
+    assert!(limiter.admit("http", "path", "/d", at(95)));
+}
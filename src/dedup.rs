@@ -0,0 +1,177 @@
+use bytes::Bytes;
+use log::warn;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use crate::point::Point;
+use crate::processors::Action;
+use crate::shared::lock;
+
+/// Which of several copies of a point survives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    /// Forward the first copy and drop the rest.
+    KeepFirst,
+    /// Hold the point for the window, merging the fields of later copies
+    /// into it, and forward it once the window is over.
+    KeepLast,
+}
+
+/// A series key and timestamp, which together identify a point.
+type Key = (Bytes, i64);
+
+#[derive(Default)]
+struct Seen {
+    /// When each point was first seen, and the merged point if it's held.
+    points: HashMap<Key, (i64, Option<Point>)>,
+    /// Keys in the order they were first seen, for expiry.
+    order: VecDeque<(Key, i64)>,
+    full: bool,
+}
+
+/// Drops or merges copies of a point seen within a window, remembering at
+/// most `max_points` points.
+pub struct Deduplicator {
+    policy: Policy,
+    /// In nanoseconds.
+    window: i64,
+    max_points: usize,
+    seen: Mutex<Seen>,
+}
+
+impl Deduplicator {
+    pub fn new(policy: Policy, window: i64, max_points: usize) -> Deduplicator {
+        Deduplicator {
+            policy,
+            window,
+            max_points,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// Removes points first seen a window or more before `now`, and also
+    /// the oldest points beyond `keep`. Returns the held ones.
+    fn expire(&self, seen: &mut Seen, now: i64, keep: usize) -> Vec<Point> {
+        let mut expired = Vec::new();
+        while let Some((_, first)) = seen.order.front() {
+            if now - *first < self.window && seen.order.len() <= keep {
+                break;
+            }
+            let (key, _) = seen.order.pop_front().unwrap();
+            if let Some((_, Some(point))) = seen.points.remove(&key) {
+                expired.push(point);
+            }
+        }
+        expired
+    }
+
+    /// Forwards or drops the point if it's new or a copy, or holds it back
+    /// to merge later copies into it. Points without a timestamp are always
+    /// forwarded, as the database stamps each when it arrives.
+    pub fn add(&self, point: &Point, now: i64) -> Action {
+        let key = match point.timestamp {
+            Some(timestamp) => (point.series_key(), timestamp),
+            None => return Action::Forward,
+        };
+        let mut seen = lock(&self.seen);
+        if self.policy == Policy::KeepFirst {
+            self.expire(&mut seen, now, self.max_points);
+        }
+        if let Some((_, held)) = seen.points.get_mut(&key) {
+            return match held {
+                Some(held) => {
+                    for (name, value) in &point.fields {
+                        match held.fields.iter_mut().find(|(n, _)| n == name) {
+                            Some((_, held)) => *held = value.clone(),
+                            None => held.fields.push((name.clone(), value.clone())),
+                        }
+                    }
+                    Action::Hold
+                }
+                None => Action::Drop,
+            };
+        }
+        if seen.points.len() >= self.max_points {
+            match self.policy {
+                // Forgetting the oldest point only risks letting a late copy through.
+                Policy::KeepFirst => {
+                    self.expire(&mut seen, now, self.max_points - 1);
+                }
+                // Held points are only let go by `flush`.
+                Policy::KeepLast => {
+                    if !seen.full {
+                        warn!(
+                            "Holding {} points for dedup, forwarding more as they come",
+                            self.max_points
+                        );
+                        seen.full = true;
+                    }
+                    return Action::Forward;
+                }
+            }
+        }
+        seen.full = false;
+        let (held, action) = match self.policy {
+            Policy::KeepFirst => (None, Action::Forward),
+            Policy::KeepLast => (Some(point.clone()), Action::Hold),
+        };
+        seen.order.push_back((key.clone(), now));
+        seen.points.insert(key, (now, held));
+        action
+    }
+
+    /// Forgets points first seen a window before `now` and returns the held
+    /// ones, in the order they were first seen.
+    pub fn flush(&self, now: i64) -> Vec<Point> {
+        let mut seen = lock(&self.seen);
+        self.expire(&mut seen, now, self.max_points)
+    }
+}
+
+#[test]
+fn check_keep_first() {
+    use crate::point::test_point;
+
+    let dedup = Deduplicator::new(Policy::KeepFirst, 100, 2);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=1 1"), 0), Action::Forward);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=2 1"), 10), Action::Drop);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=1 2"), 10), Action::Forward);
+    assert_eq!(dedup.add(&test_point(b"m,t=b v=1 1"), 20), Action::Forward);
+    // The oldest point was forgotten to make room.
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=1 1"), 20), Action::Forward);
+    assert_eq!(dedup.add(&test_point(b"m,t=b v=1 1"), 50), Action::Drop);
+    // And these after the window.
+    assert_eq!(dedup.add(&test_point(b"m,t=b v=1 1"), 120), Action::Forward);
+    assert!(dedup.flush(1000).is_empty());
+}
+
+#[test]
+fn check_keep_last_merges_fields() {
+    use crate::point::test_point;
+
+    let dedup = Deduplicator::new(Policy::KeepLast, 100, 2);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=1,w=1 1"), 0), Action::Hold);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=2,x=1 1"), 10), Action::Hold);
+    assert_eq!(dedup.add(&test_point(b"m,t=b v=1 1"), 20), Action::Hold);
+    assert_eq!(dedup.add(&test_point(b"m,t=c v=1 1"), 30), Action::Forward);
+    assert!(dedup.flush(99).is_empty());
+    let flushed: Vec<String> = dedup
+        .flush(120)
+        .iter()
+        .map(|point| String::from_utf8(point.to_line().to_vec()).unwrap())
+        .collect();
+    assert_eq!(flushed, vec!["m,t=a v=2,w=1,x=1 1\n", "m,t=b v=1 1\n"]);
+    assert_eq!(dedup.add(&test_point(b"m,t=a v=3 1"), 130), Action::Hold);
+}
+
+#[test]
+fn check_points_without_timestamp_are_not_copies() {
+    use crate::point::test_point;
+
+    for policy in &[Policy::KeepFirst, Policy::KeepLast] {
+        let dedup = Deduplicator::new(*policy, 100, 2);
+        assert_eq!(dedup.add(&test_point(b"m,t=a v=1"), 0), Action::Forward);
+        assert_eq!(dedup.add(&test_point(b"m,t=a v=2"), 10), Action::Forward);
+        assert!(dedup.flush(1000).is_empty());
+    }
+}
@@ -0,0 +1,756 @@
+use regex::Regex;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+
+use crate::point::{FieldValue, Point};
+
+/// An expression over a point, such as
+/// `tag.env == 'dev' or field.duration > 60000`.
+///
+/// Operands are `tag.<key>`, `field.<key>` (or `tag['<key>']` for keys with
+/// other characters), `measurement`, `timestamp` in nanoseconds, quoted
+/// strings, numbers and `true`/`false`. They're compared with `==`, `!=`,
+/// `<`, `<=`, `>`, `>=`, matched against a regex with `=~` or `!~`, and
+/// tested against a list with `in [...]` or `not in [...]`. Conditions are
+/// combined with `and`, `or`, `not` and parentheses.
+///
+/// Numbers can be worked out with `+`, `-`, `*`, `/` and `%`. Integers stay
+/// integers, except that `/` always gives a float; a float anywhere makes
+/// the result a float. `int(x)` truncates to an integer and `float(x)`
+/// converts to a float. `convert(x, 'bytes', 'MiB')` converts between units
+/// of size (`bits`, `bytes`, `KB` to `TB` and `KiB` to `TiB`) or of time
+/// (`ns`, `us`, `ms`, `s`, `m`, `h` and `d`), giving a float. Dividing by
+/// zero, like overflowing, leaves no value, but `value` tells it apart.
+///
+/// Testing a tag or field the point doesn't have is false, as is comparing
+/// values of different types, such as a string and a number; `!=`, `!~` and
+/// `not in` are true then. On its own, an operand is true if it's there and
+/// isn't `false`.
+pub struct Expression(Expr);
+
+/// Why an expression has no value, with a zero of the type it would have
+/// had otherwise.
+#[derive(Debug, PartialEq)]
+pub struct DivisionByZero {
+    pub zero: FieldValue,
+}
+
+/// Keeps track of division by zero while evaluating.
+#[derive(Default)]
+struct Division {
+    /// Set if dividing by zero left something without a value.
+    by_zero: Cell<bool>,
+    /// Whether dividing by zero gives zero instead, to learn the type of
+    /// the value the expression would have had.
+    as_zero: bool,
+}
+
+#[derive(Debug)]
+enum Expr {
+    Literal(FieldValue),
+    Tag(String),
+    Field(String),
+    Measurement,
+    Timestamp,
+    Neg(Box<Expr>),
+    Arithmetic(Box<Expr>, Arithmetic, Box<Expr>),
+    Int(Box<Expr>),
+    Float(Box<Expr>),
+    /// A unit conversion, as the factor to multiply by.
+    Scale(Box<Expr>, f64),
+    Compare(Box<Expr>, Compare, Box<Expr>),
+    Matches(Box<Expr>, Regex),
+    In(Box<Expr>, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arithmetic {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Str(String),
+    Integer(i64),
+    Float(f64),
+    Op(&'static str),
+}
+
+/// Operators, longest first so `<=` isn't read as `<`.
+const OPERATORS: [&str; 18] = [
+    "==", "!=", "<=", ">=", "=~", "!~", "<", ">", "(", ")", "[", "]", ",", "+", "-", "*", "/", "%",
+];
+
+/// Units of size in bytes and of time in nanoseconds.
+const UNITS: [(&str, &str, f64); 17] = [
+    ("size", "bits", 0.125),
+    ("size", "bytes", 1.0),
+    ("size", "KB", 1e3),
+    ("size", "MB", 1e6),
+    ("size", "GB", 1e9),
+    ("size", "TB", 1e12),
+    ("size", "KiB", 1024.0),
+    ("size", "MiB", 1_048_576.0),
+    ("size", "GiB", 1_073_741_824.0),
+    ("size", "TiB", 1_099_511_627_776.0),
+    ("time", "ns", 1.0),
+    ("time", "us", 1e3),
+    ("time", "ms", 1e6),
+    ("time", "s", 1e9),
+    ("time", "m", 6e10),
+    ("time", "h", 3.6e12),
+    ("time", "d", 8.64e13),
+];
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Splits the text into tokens, each with the column it starts at.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let column = text.len() - rest.len() + 1;
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+        } else if c == '\'' || c == '"' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, q)) if q == c => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, escaped)) => value.push(escaped),
+                        None => break 0,
+                    },
+                    Some((_, other)) => value.push(other),
+                    None => break 0,
+                }
+            };
+            if end == 0 {
+                return Err(format!("unterminated string at column {}", column));
+            }
+            tokens.push((column, Token::Str(value)));
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(rest.len());
+            let number = rest[..end].replace('_', "");
+            let token = match number.parse::<i64>() {
+                Ok(i) => Token::Integer(i),
+                Err(_) => Token::Float(
+                    number
+                        .parse()
+                        .map_err(|_| format!("bad number at column {}", column))?,
+                ),
+            };
+            tokens.push((column, token));
+            rest = &rest[end..];
+        } else if is_name_char(c) {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push((column, Token::Name(rest[..end].to_string())));
+            rest = &rest[end..];
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| format!("unexpected '{}' at column {}", c, column))?;
+            tokens.push((column, Token::Op(op)));
+            rest = &rest[op.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at).map(|(_, token)| token)
+    }
+
+    fn is_name(&self, name: &str) -> bool {
+        self.peek() == Some(&Token::Name(name.to_string()))
+    }
+
+    fn is_op(&self, op: &str) -> bool {
+        matches!(self.peek(), Some(Token::Op(o)) if *o == op)
+    }
+
+    fn error(&self, expected: &str) -> String {
+        match self.tokens.get(self.at) {
+            Some((column, _)) => format!("expected {} at column {}", expected, column),
+            None => format!("expected {} at the end", expected),
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<(), String> {
+        if !self.is_op(op) {
+            return Err(self.error(&format!("'{}'", op)));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.is_name("or") {
+            self.at += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.not()?;
+        while self.is_name("and") {
+            self.at += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        if self.is_name("not") {
+            self.at += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.test()
+    }
+
+    fn test(&mut self) -> Result<Expr, String> {
+        let left = Box::new(self.sum()?);
+        let op = match self.peek() {
+            Some(Token::Op(op)) => *op,
+            Some(Token::Name(name)) if name == "in" => "in",
+            Some(Token::Name(name)) if name == "not" => "not in",
+            _ => return Ok(*left),
+        };
+        let compare = match op {
+            "==" => Compare::Equal,
+            "!=" => Compare::NotEqual,
+            "<" => Compare::Less,
+            "<=" => Compare::LessOrEqual,
+            ">" => Compare::Greater,
+            ">=" => Compare::GreaterOrEqual,
+            "=~" | "!~" => {
+                self.at += 1;
+                let pattern = match self.tokens.get(self.at) {
+                    Some((_, Token::Str(pattern))) => pattern.clone(),
+                    _ => return Err(self.error("a quoted regex")),
+                };
+                let column = self.tokens[self.at].0;
+                let regex = Regex::new(&pattern)
+                    .map_err(|e| format!("bad regex at column {}: {}", column, e))?;
+                self.at += 1;
+                let matches = Expr::Matches(left, regex);
+                return Ok(if op == "!~" {
+                    Expr::Not(Box::new(matches))
+                } else {
+                    matches
+                });
+            }
+            "in" | "not in" => {
+                self.at += 1;
+                if op == "not in" {
+                    if !self.is_name("in") {
+                        return Err(self.error("'in'"));
+                    }
+                    self.at += 1;
+                }
+                self.expect_op("[")?;
+                let mut list = Vec::new();
+                while !self.is_op("]") {
+                    list.push(self.sum()?);
+                    if !self.is_op("]") {
+                        self.expect_op(",")?;
+                    }
+                }
+                self.at += 1;
+                let within = Expr::In(left, list);
+                return Ok(if op == "not in" {
+                    Expr::Not(Box::new(within))
+                } else {
+                    within
+                });
+            }
+            _ => return Ok(*left),
+        };
+        self.at += 1;
+        let right = Box::new(self.sum()?);
+        Ok(Expr::Compare(left, compare, right))
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("+")) => Arithmetic::Add,
+                Some(Token::Op("-")) => Arithmetic::Subtract,
+                _ => return Ok(expr),
+            };
+            self.at += 1;
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.operand()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Op("*")) => Arithmetic::Multiply,
+                Some(Token::Op("/")) => Arithmetic::Divide,
+                Some(Token::Op("%")) => Arithmetic::Remainder,
+                _ => return Ok(expr),
+            };
+            self.at += 1;
+            expr = Expr::Arithmetic(Box::new(expr), op, Box::new(self.operand()?));
+        }
+    }
+
+    /// A quoted unit name, as its kind and size.
+    fn unit(&mut self) -> Result<(&'static str, f64), String> {
+        let unit = match self.tokens.get(self.at) {
+            Some((_, Token::Str(unit))) => unit.clone(),
+            _ => return Err(self.error("a quoted unit")),
+        };
+        let column = self.tokens[self.at].0;
+        self.at += 1;
+        UNITS
+            .iter()
+            .find(|(_, name, _)| *name == unit)
+            .map(|(kind, _, size)| (*kind, *size))
+            .ok_or_else(|| format!("unknown unit '{}' at column {}", unit, column))
+    }
+
+    /// A call such as `int(x)` or `convert(x, 'ns', 'ms')`, after the name.
+    fn call(&mut self, name: &str, column: usize) -> Result<Expr, String> {
+        self.expect_op("(")?;
+        let arg = Box::new(self.sum()?);
+        let expr = match name {
+            "int" => Expr::Int(arg),
+            "float" => Expr::Float(arg),
+            _ => {
+                self.expect_op(",")?;
+                let (from_kind, from) = self.unit()?;
+                self.expect_op(",")?;
+                let (to_kind, to) = self.unit()?;
+                if from_kind != to_kind {
+                    return Err(format!(
+                        "can't convert {} to {} at column {}",
+                        from_kind, to_kind, column
+                    ));
+                }
+                Expr::Scale(arg, from / to)
+            }
+        };
+        self.expect_op(")")?;
+        Ok(expr)
+    }
+
+    /// A name like `tag.env`, or `tag` followed by `['key']`.
+    fn key(&mut self, name: &str, prefix: &str) -> Result<Option<String>, String> {
+        if let Some(key) = name.strip_prefix(prefix).and_then(|n| n.strip_prefix('.')) {
+            if !key.is_empty() {
+                return Ok(Some(key.to_string()));
+            }
+        }
+        if name != prefix || !self.is_op("[") {
+            return Ok(None);
+        }
+        self.at += 1;
+        let key = match self.tokens.get(self.at) {
+            Some((_, Token::Str(key))) => key.clone(),
+            _ => return Err(self.error("a quoted key")),
+        };
+        self.at += 1;
+        self.expect_op("]")?;
+        Ok(Some(key))
+    }
+
+    fn operand(&mut self) -> Result<Expr, String> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(self.error("a value")),
+        };
+        let column = self.tokens[self.at].0;
+        self.at += 1;
+        Ok(match token {
+            Token::Str(s) => Expr::Literal(FieldValue::String(s)),
+            Token::Integer(i) => Expr::Literal(FieldValue::Integer(i)),
+            Token::Float(f) => Expr::Literal(FieldValue::Float(f)),
+            Token::Op("-") => Expr::Neg(Box::new(self.operand()?)),
+            Token::Op("(") => {
+                let expr = self.or()?;
+                self.expect_op(")")?;
+                expr
+            }
+            Token::Name(name) => match name.as_str() {
+                "true" => Expr::Literal(FieldValue::Boolean(true)),
+                "false" => Expr::Literal(FieldValue::Boolean(false)),
+                "measurement" => Expr::Measurement,
+                "timestamp" => Expr::Timestamp,
+                "int" | "float" | "convert" if self.is_op("(") => self.call(&name, column)?,
+                _ => {
+                    if let Some(key) = self.key(&name, "tag")? {
+                        Expr::Tag(key)
+                    } else if let Some(key) = self.key(&name, "field")? {
+                        Expr::Field(key)
+                    } else {
+                        return Err(format!("unknown name '{}' at column {}", name, column));
+                    }
+                }
+            },
+            Token::Op(_) => {
+                self.at -= 1;
+                return Err(self.error("a value"));
+            }
+        })
+    }
+}
+
+/// Orders two values of the same kind; numbers of any type are compared
+/// by value.
+fn compare(a: &FieldValue, b: &FieldValue) -> Option<Ordering> {
+    use FieldValue::*;
+    match (a, b) {
+        (String(a), String(b)) => Some(a.cmp(b)),
+        (Boolean(a), Boolean(b)) => Some(a.cmp(b)),
+        (Integer(_), Integer(_) | UInteger(_)) | (UInteger(_), Integer(_) | UInteger(_)) => {
+            Some(integer(a)?.cmp(&integer(b)?))
+        }
+        _ => number(a)?.partial_cmp(&number(b)?),
+    }
+}
+
+fn integer(value: &FieldValue) -> Option<i128> {
+    match value {
+        FieldValue::Integer(i) => Some(i128::from(*i)),
+        FieldValue::UInteger(u) => Some(i128::from(*u)),
+        _ => None,
+    }
+}
+
+fn number(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::Float(f) => Some(*f),
+        other => integer(other).map(|i| i as f64),
+    }
+}
+
+/// Works out `a op b`. Integers overflowing, or anything that isn't a
+/// number, give no value.
+fn arithmetic(
+    a: &FieldValue,
+    op: Arithmetic,
+    b: &FieldValue,
+    division: &Division,
+) -> Option<FieldValue> {
+    if let (Some(x), Some(y), false) = (integer(a), integer(b), op == Arithmetic::Divide) {
+        let result = match op {
+            Arithmetic::Add => x + y,
+            Arithmetic::Subtract => x - y,
+            Arithmetic::Multiply => x.checked_mul(y)?,
+            _ if y == 0 => {
+                division.by_zero.set(true);
+                if !division.as_zero {
+                    return None;
+                }
+                0
+            }
+            _ => x % y,
+        };
+        let unsigned = matches!((a, b), (FieldValue::UInteger(_), FieldValue::UInteger(_)));
+        return match u64::try_from(result) {
+            Ok(u) if unsigned => Some(FieldValue::UInteger(u)),
+            _ => i64::try_from(result).ok().map(FieldValue::Integer),
+        };
+    }
+    let (x, y) = (number(a)?, number(b)?);
+    let result = match op {
+        Arithmetic::Add => x + y,
+        Arithmetic::Subtract => x - y,
+        Arithmetic::Multiply => x * y,
+        _ if y == 0.0 => {
+            division.by_zero.set(true);
+            if !division.as_zero {
+                return None;
+            }
+            0.0
+        }
+        Arithmetic::Divide => x / y,
+        Arithmetic::Remainder => x % y,
+    };
+    // Line protocol has no infinities or NaN.
+    if result.is_finite() {
+        Some(FieldValue::Float(result))
+    } else {
+        None
+    }
+}
+
+fn truthy(value: &Option<FieldValue>) -> bool {
+    match value {
+        None => false,
+        Some(FieldValue::Boolean(b)) => *b,
+        Some(_) => true,
+    }
+}
+
+impl Expr {
+    /// The value of the expression, or `None` if it refers to a tag or field
+    /// the point doesn't have.
+    /// `division.by_zero` is set if dividing by zero is why there's none.
+    fn eval(&self, point: &Point, division: &Division) -> Option<FieldValue> {
+        let test = |b| Some(FieldValue::Boolean(b));
+        match self {
+            Expr::Literal(value) => Some(value.clone()),
+            Expr::Tag(key) => point
+                .tags
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| FieldValue::String(value.clone())),
+            Expr::Field(key) => point
+                .fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value.clone()),
+            Expr::Measurement => Some(FieldValue::String(point.measurement.clone())),
+            Expr::Timestamp => point.timestamp.map(FieldValue::Integer),
+            Expr::Arithmetic(left, op, right) => {
+                let left = left.eval(point, division)?;
+                arithmetic(&left, *op, &right.eval(point, division)?, division)
+            }
+            Expr::Int(expr) => match expr.eval(point, division)? {
+                FieldValue::Float(f) if f.abs() < 9.2e18 => Some(FieldValue::Integer(f as i64)),
+                value @ FieldValue::Integer(_) | value @ FieldValue::UInteger(_) => Some(value),
+                _ => None,
+            },
+            Expr::Float(expr) => number(&expr.eval(point, division)?).map(FieldValue::Float),
+            Expr::Scale(expr, factor) => number(&expr.eval(point, division)?)
+                .map(|n| n * factor)
+                .filter(|n| n.is_finite())
+                .map(FieldValue::Float),
+            Expr::Neg(expr) => match expr.eval(point, division)? {
+                FieldValue::Integer(i) => Some(FieldValue::Integer(i.checked_neg()?)),
+                FieldValue::UInteger(u) => Some(FieldValue::Integer(0i64.checked_sub_unsigned(u)?)),
+                FieldValue::Float(f) => Some(FieldValue::Float(-f)),
+                _ => None,
+            },
+            Expr::Compare(left, op, right) => {
+                let ordering = match (left.eval(point, division), right.eval(point, division)) {
+                    (Some(left), Some(right)) => compare(&left, &right),
+                    _ => None,
+                };
+                test(match (op, ordering) {
+                    (Compare::NotEqual, ordering) => ordering != Some(Ordering::Equal),
+                    (_, None) => false,
+                    (Compare::Equal, Some(o)) => o == Ordering::Equal,
+                    (Compare::Less, Some(o)) => o == Ordering::Less,
+                    (Compare::LessOrEqual, Some(o)) => o != Ordering::Greater,
+                    (Compare::Greater, Some(o)) => o == Ordering::Greater,
+                    (Compare::GreaterOrEqual, Some(o)) => o != Ordering::Less,
+                })
+            }
+            Expr::Matches(expr, regex) => match expr.eval(point, division) {
+                Some(FieldValue::String(s)) => test(regex.is_match(&s)),
+                _ => test(false),
+            },
+            Expr::In(expr, list) => match expr.eval(point, division) {
+                Some(value) => test(list.iter().any(|item| {
+                    item.eval(point, division)
+                        .and_then(|item| compare(&value, &item))
+                        .is_some_and(|o| o == Ordering::Equal)
+                })),
+                None => test(false),
+            },
+            Expr::Not(expr) => test(!truthy(&expr.eval(point, division))),
+            Expr::And(left, right) => {
+                test(truthy(&left.eval(point, division)) && truthy(&right.eval(point, division)))
+            }
+            Expr::Or(left, right) => {
+                test(truthy(&left.eval(point, division)) || truthy(&right.eval(point, division)))
+            }
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            at: 0,
+        };
+        let expr = parser.or()?;
+        if parser.at < parser.tokens.len() {
+            return Err(parser.error("'and', 'or' or the end"));
+        }
+        Ok(Expression(expr))
+    }
+
+    /// Whether the point matches the expression as a condition.
+    pub fn matches(&self, point: &Point) -> bool {
+        truthy(&self.0.eval(point, &Division::default()))
+    }
+
+    /// The value of the expression for the point, if it has one.
+    pub fn value(&self, point: &Point) -> Result<Option<FieldValue>, DivisionByZero> {
+        let division = Division::default();
+        let value = self.0.eval(point, &division);
+        if value.is_none() && division.by_zero.get() {
+            let division = Division {
+                as_zero: true,
+                ..Division::default()
+            };
+            let zero = match self.0.eval(point, &division) {
+                Some(FieldValue::Integer(_)) => FieldValue::Integer(0),
+                Some(FieldValue::UInteger(_)) => FieldValue::UInteger(0),
+                _ => FieldValue::Float(0.0),
+            };
+            return Err(DivisionByZero { zero });
+        }
+        Ok(value)
+    }
+}
+
+#[test]
+fn check_conditions() {
+    use crate::point::test_point;
+
+    let point = test_point(
+        b"http,env=dev,path=/api/users duration=75000i,ok=true,ratio=0.5,host=\"web-1\" 1000",
+    );
+    let matches = |text: &str| Expression::parse(text).unwrap().matches(&point);
+    assert!(matches("tag.env == 'dev' or field.duration > 60000"));
+    assert!(!matches("tag.env != 'dev'"));
+    assert!(matches(
+        "field.duration >= 75000 and field.duration <= 75000.0"
+    ));
+    assert!(matches("field.ratio < 1 and field.ratio > -1"));
+    assert!(matches("field.ok and not field.missing"));
+    assert!(matches("measurement == \"http\" and timestamp < 2000"));
+    assert!(matches("tag.path =~ '^/api/' and field.host !~ 'db'"));
+    assert!(matches(
+        "tag.env in ['dev', 'test'] and tag.env not in ['prod']"
+    ));
+    assert!(matches("tag['env'] == 'dev' and (false or tag.path)"));
+    // Tests of missing tags, or of values of different types, are false
+    // unless they're negated.
+    assert!(!matches("tag.region == 'eu'"));
+    assert!(!matches("tag.region < 'eu' or tag.region >= 'eu'"));
+    assert!(!matches("tag.region =~ '.*'"));
+    assert!(matches("tag.region != 'eu'"));
+    assert!(matches("not tag.region == 'eu'"));
+    assert!(matches("tag.region not in ['eu']"));
+    assert!(!matches("field.duration == '75000'"));
+    assert!(matches("field.duration != '75000'"));
+}
+
+#[test]
+fn check_syntax_errors() {
+    let error = |text: &str| Expression::parse(text).err().unwrap();
+    assert_eq!(error("tag.env = 'dev'"), "unexpected '=' at column 9");
+    assert_eq!(error("tag.env == 'dev"), "unterminated string at column 12");
+    assert_eq!(error("tag.env =="), "expected a value at the end");
+    assert_eq!(error("env == 'dev'"), "unknown name 'env' at column 1");
+    assert_eq!(
+        error("tag.env == 'dev' tag.x"),
+        "expected 'and', 'or' or the end at column 18"
+    );
+    assert!(error("tag.env =~ '('").starts_with("bad regex at column 12"));
+    assert_eq!(error("tag.env in ['a' 'b']"), "expected ',' at column 17");
+}
+
+#[test]
+fn check_arithmetic() {
+    use crate::point::test_point;
+
+    let point = test_point(
+        b"rpc duration_us=1500i,errors=3i,requests=4i,zero=0i,bytes=3145728u,elapsed=2.5 1",
+    );
+    let value = |text: &str| Expression::parse(text).unwrap().value(&point);
+    assert_eq!(
+        value("field.duration_us / 1000"),
+        Ok(Some(FieldValue::Float(1.5)))
+    );
+    assert_eq!(
+        value("field.errors / field.requests"),
+        Ok(Some(FieldValue::Float(0.75)))
+    );
+    assert_eq!(
+        value("int(field.duration_us / 1000) * 2 + 1"),
+        Ok(Some(FieldValue::Integer(3)))
+    );
+    assert_eq!(
+        value("field.requests - field.errors * 2"),
+        Ok(Some(FieldValue::Integer(-2)))
+    );
+    assert_eq!(
+        value("field.bytes % 1000"),
+        Ok(Some(FieldValue::Integer(728)))
+    );
+    assert_eq!(
+        value("field.bytes + field.bytes"),
+        Ok(Some(FieldValue::UInteger(6_291_456)))
+    );
+    assert_eq!(value("field.elapsed * 2"), Ok(Some(FieldValue::Float(5.0))));
+    assert_eq!(
+        value("convert(field.bytes, 'bytes', 'MiB')"),
+        Ok(Some(FieldValue::Float(3.0)))
+    );
+    assert_eq!(
+        value("convert(field.duration_us, 'us', 'ms')"),
+        Ok(Some(FieldValue::Float(1.5)))
+    );
+    let zero = |zero| Err(DivisionByZero { zero });
+    assert_eq!(
+        value("field.errors / field.zero"),
+        zero(FieldValue::Float(0.0))
+    );
+    assert_eq!(value("field.errors % 0"), zero(FieldValue::Integer(0)));
+    assert_eq!(
+        value("int(field.errors / field.zero) + 1"),
+        zero(FieldValue::Integer(0))
+    );
+    assert_eq!(value("convert(1e308, 'GiB', 'bytes')"), Ok(None));
+    assert_eq!(value("field.missing / field.zero"), Ok(None));
+    assert_eq!(value("9223372036854775807 + 1"), Ok(None));
+    assert!(!Expression::parse("field.errors / field.zero > 1")
+        .unwrap()
+        .matches(&point));
+    assert!(Expression::parse("field.errors * 2 > field.requests + 1")
+        .unwrap()
+        .matches(&point));
+    assert_eq!(
+        Expression::parse("convert(field.bytes, 'bytes', 'ms')").err(),
+        Some(String::from("can't convert size to time at column 1"))
+    );
+    assert_eq!(
+        Expression::parse("convert(field.bytes, 'bytes', 'MiBs')").err(),
+        Some(String::from("unknown unit 'MiBs' at column 31"))
+    );
+}
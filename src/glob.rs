@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+/// (including none) and `?` matches exactly one. Every other character
+/// matches itself.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*`, if the match so far fails.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    n = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A list of names and glob patterns. Plain names are looked up in a set, so
+/// long lists of them stay cheap.
+#[derive(Debug, Default)]
+pub struct GlobSet {
+    names: HashSet<String>,
+    patterns: Vec<String>,
+}
+
+impl GlobSet {
+    pub fn new(entries: &[String]) -> GlobSet {
+        let mut set = GlobSet::default();
+        for entry in entries {
+            if entry.contains(['*', '?']) {
+                set.patterns.push(entry.clone());
+            } else {
+                set.names.insert(entry.clone());
+            }
+        }
+        set
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.patterns.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.names.contains(name) || self.patterns.iter().any(|p| matches(p, name))
+    }
+}
+
+#[test]
+fn check_glob_matches() {
+    let cases = vec![
+        ("host", "host", true),
+        ("host", "hostname", false),
+        ("host*", "hostname", true),
+        ("host*", "host", true),
+        ("*_id", "product_id", true),
+        ("*_id", "product_ids", false),
+        ("a*b*c", "axxbyybc", true),
+        ("a*b*c", "axxbyybd", false),
+        ("?ost", "host", true),
+        ("?ost", "ost", false),
+        ("h?st", "hést", true),
+        ("**", "", true),
+        ("", "", true),
+        ("", "a", false),
+    ];
+    for (pattern, name, expected) in cases {
+        assert_eq!(matches(pattern, name), expected, "{} {}", pattern, name);
+    }
+}
+
+#[test]
+fn check_glob_set() {
+    let set = GlobSet::new(&[String::from("host"), String::from("user_*")]);
+    assert!(set.matches("host"));
+    assert!(set.matches("user_id"));
+    assert!(!set.matches("hostname"));
+    assert!(GlobSet::new(&[]).is_empty());
+}
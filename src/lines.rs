@@ -1,11 +1,58 @@
 use bytes::{Bytes, BytesMut};
 use futures::{Async, Poll, Stream};
 use hyper::{Chunk, Error};
-use std::collections::VecDeque;
+use std::fmt;
 
+use crate::settings::Limits;
+
+/// Why a request body could not be framed into lines.
+#[derive(Debug)]
+pub enum ReadError {
+    Body(Error),
+    BodyTooLarge { limit: usize },
+    LineTooLong { line: usize, limit: usize },
+    TooManyLines { limit: usize },
+}
+
+impl From<Error> for ReadError {
+    fn from(e: Error) -> Self {
+        ReadError::Body(e)
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Body(e) => write!(f, "error reading body: {}", e),
+            ReadError::BodyTooLarge { limit } => {
+                write!(f, "request body exceeds {} bytes", limit)
+            }
+            ReadError::LineTooLong { line, limit } => {
+                write!(f, "line {} exceeds {} bytes", line, limit)
+            }
+            ReadError::TooManyLines { limit } => {
+                write!(f, "request contains more than {} lines", limit)
+            }
+        }
+    }
+}
+
+/// Frames a stream of body chunks into lines.
+///
+/// Each line is yielded exactly once, with its trailing `\n` if it had one.
+/// Empty lines and comments are yielded too, and a `\r` before the newline
+/// is kept; the pipeline skips or strips them. A final line without a newline
+/// is yielded when the body ends. A line that lies within a single chunk is
+/// sliced from it without copying.
 pub struct Reader<S> {
-    len: usize,
-    items: VecDeque<Chunk>,
+    /// Start of a line carried over from earlier chunks.
+    partial: BytesMut,
+    /// The part of the latest chunk that hasn't been framed yet.
+    current: Bytes,
+    done: bool,
+    body_bytes: usize,
+    lines: usize,
+    limits: Limits,
     stream: S,
 }
 
@@ -13,91 +60,181 @@ impl<S> Reader<S>
 where
     S: Stream<Item = Chunk, Error = Error>,
 {
-    pub fn new(stream: S) -> Self {
+    pub fn new(stream: S, limits: Limits) -> Self {
         Reader {
-            len: 0,
-            items: VecDeque::new(),
+            partial: BytesMut::new(),
+            current: Bytes::new(),
+            done: false,
+            body_bytes: 0,
+            lines: 0,
+            limits,
             stream,
         }
     }
 
-    #[inline]
-    fn poll_stream(&mut self) -> Poll<bool, Error> {
-        self.stream.poll().map(|res| match res {
-            Async::Ready(Some(data)) => {
-                self.len += data.len();
-                self.items.push_back(data);
-                Async::Ready(true)
-            }
-            Async::Ready(None) => Async::Ready(false),
-            Async::NotReady => Async::NotReady,
-        })
+    /// Counts a line about to be yielded, checking it against the limits.
+    /// `length` excludes the trailing newline.
+    fn check_line(&mut self, length: usize) -> Result<(), ReadError> {
+        self.lines += 1;
+        if self.lines > self.limits.max_lines {
+            return Err(ReadError::TooManyLines {
+                limit: self.limits.max_lines,
+            });
+        }
+        if length > self.limits.max_line_bytes {
+            return Err(ReadError::LineTooLong {
+                line: self.lines,
+                limit: self.limits.max_line_bytes,
+            });
+        }
+        Ok(())
     }
 
-    fn read_until(&mut self, until: u8) -> Poll<Option<Bytes>, Error> {
-        let mut length = 0;
-        let mut num = 0;
-        let mut offset = 0;
-
-        for i in 0..self.items.len() {
-            let chunk = &self.items[i];
-            let pos = chunk.iter().position(|&c| c == until);
-            let found = match pos {
-                Some(p) => {
-                    if p > 0 {
-                        num = i;
-                        offset = p + 1;
-                        length += p + 1;
-                        true
-                    } else {
-                        length += chunk.len();
-                        false
-                    }
-                }
-                None => {
-                    length += chunk.len();
-                    false
+    fn read_until(&mut self, until: u8) -> Poll<Option<Bytes>, ReadError> {
+        loop {
+            if let Some(p) = self.current.iter().position(|&c| c == until) {
+                self.check_line(self.partial.len() + p)?;
+                let line = self.current.split_to(p + 1);
+                if self.partial.is_empty() {
+                    return Ok(Async::Ready(Some(line)));
                 }
-            };
-            if found {
-                return self.compose_line(length, num, offset);
+                self.partial.extend_from_slice(&line);
+                return Ok(Async::Ready(Some(self.partial.take().freeze())));
             }
-        }
 
-        match self.poll_stream()? {
-            Async::Ready(true) => self.read_until(until),
-            Async::Ready(false) => Ok(Async::Ready(None)),
-            Async::NotReady => Ok(Async::NotReady),
-        }
-    }
+            if !self.current.is_empty() {
+                // Fail as soon as an unterminated line is too long, so it's never buffered whole.
+                if self.partial.len() + self.current.len() > self.limits.max_line_bytes {
+                    return Err(ReadError::LineTooLong {
+                        line: self.lines + 1,
+                        limit: self.limits.max_line_bytes,
+                    });
+                }
+                self.partial.extend_from_slice(&self.current);
+                self.current.clear();
+            }
 
-    fn compose_line(
-        &mut self,
-        length: usize,
-        num: usize,
-        offset: usize,
-    ) -> Result<Async<Option<Bytes>>, Error> {
-        let mut buf = BytesMut::with_capacity(length);
-        if num > 0 {
-            for _ in 0..num {
-                buf.extend_from_slice(&self.items.pop_front().unwrap());
+            if self.done {
+                if self.partial.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                self.check_line(self.partial.len())?;
+                return Ok(Async::Ready(Some(self.partial.take().freeze())));
             }
-        }
-        if offset > 0 {
-            let chunk = self.items.pop_front().unwrap();
-            let (first, last) = chunk.split_at(offset);
-            buf.extend_from_slice(&first);
-            if last.len() > 0 {
-                self.items.push_front(Chunk::from(last.to_vec()));
+
+            match self.stream.poll()? {
+                Async::Ready(Some(chunk)) => {
+                    self.body_bytes += chunk.len();
+                    if self.body_bytes > self.limits.max_body_bytes {
+                        return Err(ReadError::BodyTooLarge {
+                            limit: self.limits.max_body_bytes,
+                        });
+                    }
+                    self.current = chunk.into_bytes();
+                }
+                Async::Ready(None) => self.done = true,
+                Async::NotReady => return Ok(Async::NotReady),
             }
         }
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        self.len -= length;
-        let x = Ok(Async::Ready(Some(buf.freeze())));
-        x
     }
 
-    pub fn read_line(&mut self) -> Poll<Option<Bytes>, Error> {
+    pub fn read_line(&mut self) -> Poll<Option<Bytes>, ReadError> {
         self.read_until(b'\n')
     }
 }
+
+impl<S> Stream for Reader<S>
+where
+    S: Stream<Item = Chunk, Error = Error>,
+{
+    type Item = Bytes;
+    type Error = ReadError;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, ReadError> {
+        self.read_line()
+    }
+}
+
+#[cfg(test)]
+fn read_with_limits(chunks: Vec<&[u8]>, limits: Limits) -> Result<Vec<Bytes>, ReadError> {
+    use futures::Future;
+
+    let chunks: Vec<Chunk> = chunks
+        .into_iter()
+        .map(|c| Chunk::from(c.to_vec()))
+        .collect();
+    Reader::new(futures::stream::iter_ok::<_, Error>(chunks), limits)
+        .collect()
+        .wait()
+}
+
+#[cfg(test)]
+fn read_all(chunks: Vec<&[u8]>) -> Vec<Bytes> {
+    read_with_limits(chunks, Limits::default()).unwrap()
+}
+
+#[test]
+fn check_lines_split_at_every_offset() {
+    let body: &[u8] = b"\ncpu,host=a usage=1 1\n\nmem free=2i\nlast value=3";
+    let expected: Vec<&[u8]> = vec![
+        b"\n",
+        b"cpu,host=a usage=1 1\n",
+        b"\n",
+        b"mem free=2i\n",
+        b"last value=3",
+    ];
+    for i in 0..=body.len() {
+        let lines = read_all(vec![&body[..i], &body[i..]]);
+        assert_eq!(lines, expected, "split at {}", i);
+        for j in i..=body.len() {
+            let lines = read_all(vec![&body[..i], &body[i..j], &body[j..]]);
+            assert_eq!(lines, expected, "split at {} and {}", i, j);
+        }
+    }
+}
+
+#[test]
+fn check_lines_one_byte_chunks() {
+    let body: &[u8] = b"a v=1\n\nb v=2\n";
+    let lines = read_all(body.chunks(1).collect());
+    let expected: Vec<&[u8]> = vec![b"a v=1\n", b"\n", b"b v=2\n"];
+    assert_eq!(lines, expected);
+}
+
+#[test]
+fn check_lines_empty_body() {
+    assert!(read_all(vec![]).is_empty());
+    assert!(read_all(vec![b"", b""]).is_empty());
+}
+
+#[test]
+fn check_lines_limits() {
+    let limits = Limits {
+        max_body_bytes: 20,
+        max_line_bytes: 6,
+        max_lines: 2,
+        ..Limits::default()
+    };
+    assert_eq!(
+        read_with_limits(vec![b"a v=1\n", b"b v=2"], limits)
+            .unwrap()
+            .len(),
+        2
+    );
+    match read_with_limits(vec![b"a v=1\nb v", b"=222\n"], limits) {
+        Err(ReadError::LineTooLong { line: 2, limit: 6 }) => {}
+        r => panic!("Expected line too long: {:?}", r),
+    }
+    match read_with_limits(vec![b"a v=1\n", b"abcdefgh"], limits) {
+        Err(ReadError::LineTooLong { line: 2, limit: 6 }) => {}
+        r => panic!("Expected line too long: {:?}", r),
+    }
+    match read_with_limits(vec![b"a v=1\nb v=2\nc v=3\n"], limits) {
+        Err(ReadError::TooManyLines { limit: 2 }) => {}
+        r => panic!("Expected too many lines: {:?}", r),
+    }
+    match read_with_limits(vec![b"a v=1\n", b"b v=2\n", b"\n\n\n\n\n\n\n\n\n"], limits) {
+        Err(ReadError::BodyTooLarge { limit: 20 }) => {}
+        r => panic!("Expected body too large: {:?}", r),
+    }
+}
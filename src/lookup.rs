@@ -0,0 +1,249 @@
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The columns of each row, sorted by name, keyed by the row's key column.
+pub type Table = HashMap<String, Vec<(String, String)>>;
+
+/// What identifies a version of the file: its modification time and size.
+type Version = (Option<SystemTime>, u64);
+
+/// A table loaded from a CSV or JSON file, reloaded when the file changes.
+///
+/// CSV files need a header row. JSON files hold either an array of objects
+/// or an object of objects keyed by the key column. Empty and null values
+/// are left out, since they can't become tags.
+pub struct LookupTable {
+    path: PathBuf,
+    key_column: String,
+    /// Columns to keep; all but the key column if `None`.
+    columns: Option<Vec<String>>,
+    check_interval: Duration,
+    table: RwLock<Arc<Table>>,
+    /// When the file was last checked, and which version was loaded.
+    checked: Mutex<(Instant, Version)>,
+}
+
+fn version(path: &Path) -> Result<Version, String> {
+    let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+fn json_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+impl LookupTable {
+    pub fn open(
+        path: &str,
+        key_column: &str,
+        columns: Option<Vec<String>>,
+        check_interval: Duration,
+    ) -> Result<LookupTable, String> {
+        let mut lookup = LookupTable {
+            path: PathBuf::from(path),
+            key_column: key_column.to_string(),
+            columns,
+            check_interval,
+            table: RwLock::new(Arc::new(Table::new())),
+            checked: Mutex::new((Instant::now(), (None, 0))),
+        };
+        let version = version(&lookup.path).map_err(|e| format!("{}: {}", path, e))?;
+        let table = lookup.load().map_err(|e| format!("{}: {}", path, e))?;
+        lookup.table = RwLock::new(Arc::new(table));
+        lookup.checked = Mutex::new((Instant::now(), version));
+        Ok(lookup)
+    }
+
+    fn row(&self, mut row: Vec<(String, String)>) -> Option<(String, Vec<(String, String)>)> {
+        let at = row
+            .iter()
+            .position(|(column, _)| *column == self.key_column)?;
+        let (_, key) = row.swap_remove(at);
+        row.retain(|(column, value)| {
+            !value.is_empty() && self.columns.as_ref().is_none_or(|c| c.contains(column))
+        });
+        // A line break in a tag would end the line it's written in.
+        let line_break = |s: &str| s.contains(&['\n', '\r'][..]);
+        row.retain(|(column, value)| {
+            if line_break(column) || line_break(value) {
+                warn!(
+                    "Left out column {:?} of row {:?}, which has a line break",
+                    column, key
+                );
+                return false;
+            }
+            true
+        });
+        row.sort();
+        Some((key, row))
+    }
+
+    fn load(&self) -> Result<Table, String> {
+        let is_json = self
+            .path
+            .extension()
+            .is_some_and(|extension| extension == "json");
+        let mut rows = Vec::new();
+        if is_json {
+            let text = fs::read_to_string(&self.path).map_err(|e| e.to_string())?;
+            let json: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+            let objects: Vec<(Option<String>, &Value)> = match &json {
+                Value::Array(items) => items.iter().map(|item| (None, item)).collect(),
+                Value::Object(map) => map.iter().map(|(k, v)| (Some(k.clone()), v)).collect(),
+                _ => return Err(String::from("expected an array or object of objects")),
+            };
+            for (key, object) in objects {
+                let object = object
+                    .as_object()
+                    .ok_or_else(|| String::from("expected an object for each row"))?;
+                let mut row: Vec<(String, String)> = object
+                    .iter()
+                    .filter_map(|(column, value)| Some((column.clone(), json_string(value)?)))
+                    .collect();
+                if let Some(key) = key {
+                    row.push((self.key_column.clone(), key));
+                }
+                rows.push(row);
+            }
+        } else {
+            let mut reader = csv::Reader::from_path(&self.path).map_err(|e| e.to_string())?;
+            let headers = reader.headers().map_err(|e| e.to_string())?.clone();
+            if !headers.iter().any(|h| h == self.key_column) {
+                return Err(format!("no {} column", self.key_column));
+            }
+            for record in reader.records() {
+                let record = record.map_err(|e| e.to_string())?;
+                rows.push(
+                    headers
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(h, v)| (h.to_string(), v.to_string()))
+                        .collect(),
+                );
+            }
+        }
+        Ok(rows.into_iter().filter_map(|row| self.row(row)).collect())
+    }
+
+    /// Reloads the table if the file changed since it was last checked.
+    /// A file that can't be read or parsed leaves the old table in place.
+    fn reload_if_changed(&self) {
+        // Only one worker checks at a time; the others carry on with the old table.
+        let mut checked = match self.checked.try_lock() {
+            Ok(checked) => checked,
+            Err(_) => return,
+        };
+        if checked.0.elapsed() < self.check_interval {
+            return;
+        }
+        checked.0 = Instant::now();
+        let version = match version(&self.path) {
+            Ok(version) if version != checked.1 => version,
+            Ok(_) => return,
+            Err(e) => {
+                warn!("Can't check lookup table {}: {}", self.path.display(), e);
+                return;
+            }
+        };
+        match self.load() {
+            Ok(table) => {
+                info!(
+                    "Reloaded lookup table {} with {} rows",
+                    self.path.display(),
+                    table.len()
+                );
+                *self.table.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(table);
+                checked.1 = version;
+            }
+            Err(e) => warn!("Can't reload lookup table {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// The current table, reloaded first if the file has changed.
+    pub fn table(&self) -> Arc<Table> {
+        self.reload_if_changed();
+        self.table.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[test]
+fn check_csv_table_reloads() {
+    let path = std::env::temp_dir().join(format!("interflux-lookup-{}.csv", std::process::id()));
+    fs::write(
+        &path,
+        "id,category,brand\n42,toys,acme\n7,\"books, used\",\n1,\"a\nb\",acme\n",
+    )
+    .unwrap();
+    let lookup =
+        LookupTable::open(path.to_str().unwrap(), "id", None, Duration::from_secs(0)).unwrap();
+    let table = lookup.table();
+    assert_eq!(
+        table["42"],
+        vec![
+            (String::from("brand"), String::from("acme")),
+            (String::from("category"), String::from("toys"))
+        ]
+    );
+    assert_eq!(
+        table["7"],
+        vec![(String::from("category"), String::from("books, used"))]
+    );
+    assert_eq!(
+        table["1"],
+        vec![(String::from("brand"), String::from("acme"))]
+    );
+
+    fs::write(&path, "id,category\n42,games\n").unwrap();
+    assert_eq!(
+        lookup.table()["42"],
+        vec![(String::from("category"), String::from("games"))]
+    );
+    fs::write(&path, "\"unterminated\n").unwrap();
+    assert_eq!(lookup.table()["42"][0].1, "games");
+    fs::remove_file(&path).unwrap();
+    assert!(LookupTable::open(path.to_str().unwrap(), "id", None, Duration::from_secs(0)).is_err());
+}
+
+#[test]
+fn check_json_tables() {
+    let path = std::env::temp_dir().join(format!("interflux-lookup-{}.json", std::process::id()));
+    let columns = Some(vec![String::from("category")]);
+    fs::write(
+        &path,
+        r#"[{"id": 42, "category": "toys", "brand": "acme"}, {"id": "7", "category": null}]"#,
+    )
+    .unwrap();
+    let lookup = LookupTable::open(
+        path.to_str().unwrap(),
+        "id",
+        columns.clone(),
+        Duration::from_secs(0),
+    )
+    .unwrap();
+    let table = lookup.table();
+    assert_eq!(
+        table["42"],
+        vec![(String::from("category"), String::from("toys"))]
+    );
+    assert!(table["7"].is_empty());
+
+    fs::write(&path, r#"{"42": {"category": "games"}}"#).unwrap();
+    let lookup = LookupTable::open(
+        path.to_str().unwrap(),
+        "id",
+        columns,
+        Duration::from_secs(0),
+    )
+    .unwrap();
+    assert_eq!(lookup.table()["42"][0].1, "games");
+    fs::remove_file(&path).unwrap();
+}
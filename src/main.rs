@@ -1,57 +1,241 @@
-use log::error;
-
-use std::collections::HashMap;
-use std::sync::Arc;
+use log::{error, info, warn};
+use serde_json::json;
 
+use hyper::header::CONTENT_LENGTH;
 use hyper::{rt::Future, service::service_fn, Body, Method, Request, Response, Server, StatusCode};
 
-use futures::future;
-use futures::stream::{poll_fn, Stream};
+use futures::future::{self, Either};
+use futures::stream::Stream;
 
+mod aggregate;
+mod cardinality;
+mod dedup;
+mod expr;
+mod glob;
 mod lines;
+mod lookup;
+mod output;
 mod parser;
+mod pipeline;
+mod point;
 mod processors;
+mod rate;
+mod redact;
+mod sample;
 mod settings;
+mod shared;
+mod sketch;
 
-use bytes::Bytes;
-use crate::lines::Reader;
-use crate::parser::get_measurement_name;
-use crate::processors::MetricProcessor;
-use crate::settings::Settings;
-use futures::Poll;
+use crate::lines::{ReadError, Reader};
+use crate::output::{Batch, Output, QueueClosed};
+use crate::pipeline::{Pipeline, WriteSummary};
+use crate::point::precision_nanos;
+use crate::processors::{MetricProcessor, Routes};
+use crate::settings::{Limits, Settings};
 
 use clap::{App, Arg, ArgMatches};
+use env_logger::Env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::timer::Interval;
+
+/// How often held points are checked for being due.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Why a write request could not be completed.
+enum WriteError {
+    Body(ReadError),
+    QueueClosed,
+}
+
+impl From<QueueClosed> for WriteError {
+    fn from(_: QueueClosed) -> Self {
+        WriteError::QueueClosed
+    }
+}
+
+/// State threaded through the lines of a single write request.
+struct WriteState {
+    summary: WriteSummary,
+    batch: Batch,
+    output: Output,
+    /// Points already sent, which outlives the state if the request fails.
+    written: Arc<AtomicUsize>,
+}
+
+impl WriteState {
+    fn new(output: Output, written: Arc<AtomicUsize>) -> Self {
+        WriteState {
+            summary: WriteSummary::default(),
+            batch: Vec::with_capacity(output.batch_size),
+            output,
+            written,
+        }
+    }
+
+    /// Sends the current batch, waiting for room in the output queue.
+    fn flush(self) -> impl Future<Item = WriteState, Error = WriteError> {
+        let WriteState {
+            summary,
+            batch,
+            output,
+            written,
+        } = self;
+        if batch.is_empty() {
+            return Either::A(future::ok(WriteState {
+                summary,
+                batch,
+                output,
+                written,
+            }));
+        }
+        let capacity = output.batch_size;
+        let sent = batch.len();
+        Either::B(output.send(batch).from_err().map(move |output| {
+            written.fetch_add(sent, Ordering::Relaxed);
+            WriteState {
+                summary,
+                batch: Vec::with_capacity(capacity),
+                output,
+                written,
+            }
+        }))
+    }
+}
 
-type BoxFut = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+/// A JSON error response in the style InfluxDB uses.
+fn error_response(
+    mut response: Response<Body>,
+    status: StatusCode,
+    message: String,
+) -> Response<Body> {
+    *response.status_mut() = status;
+    *response.body_mut() = Body::from(json!({ "error": message }).to_string());
+    response
+}
+
+fn content_length(req: &Request<Body>) -> Option<usize> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
 
-fn run(buf: &[u8], processors: Arc<HashMap<String, MetricProcessor>>) -> usize {
-    let measurement_name = get_measurement_name(buf);
-    match measurement_name {
-        Some((remaining, name)) => match processors.get(name) {
-            Some(processor) => processor.process(name, remaining),
-            None => 0,
-        },
-        None => 0,
+/// Nanoseconds per unit of the write's timestamps, from its `precision`
+/// parameter. Timestamps are in nanoseconds if it's not given.
+fn write_precision(req: &Request<Body>) -> Result<i64, String> {
+    let precision = req
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == "precision");
+    match precision {
+        Some((_, value)) => precision_nanos(value)
+            .ok_or_else(|| format!("invalid precision \"{}\" (use n, u, ms, s, m or h)", value)),
+        None => Ok(1),
     }
 }
 
-fn intercept(req: Request<Body>, processors: Arc<HashMap<String, MetricProcessor>>) -> BoxFut {
+fn intercept(req: Request<Body>, pipeline: Pipeline, output: Output, limits: Limits) -> BoxFut {
     let mut response = Response::new(Body::empty());
     match (req.method(), req.uri().path()) {
         (&Method::POST, "/write") => {
             println!("/write");
+            if content_length(&req).is_some_and(|length| length > limits.max_body_bytes) {
+                let error = ReadError::BodyTooLarge {
+                    limit: limits.max_body_bytes,
+                };
+                return Box::new(future::ok(error_response(
+                    response,
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    error.to_string(),
+                )));
+            }
+            let precision = match write_precision(&req) {
+                Ok(precision) => precision,
+                Err(message) => {
+                    return Box::new(future::ok(error_response(
+                        response,
+                        StatusCode::BAD_REQUEST,
+                        message,
+                    )));
+                }
+            };
             let body = req.into_body();
-            let mut reader = Reader::new(body);
-
-            let mapping =
-                poll_fn(move || -> Poll<Option<Bytes>, hyper::Error> { reader.read_line() })
-                    .fold(0, move |counter, buf| {
-                        run(&buf, processors.clone());
-                        future::ok::<_, hyper::Error>(counter + 1)
-                    }).then(move |_| {
-                        *response.status_mut() = StatusCode::OK;
-                        future::ok::<_, hyper::Error>(response)
-                    });
+            let written = Arc::new(AtomicUsize::new(0));
+
+            let mapping = pipeline
+                .process(Reader::new(body, limits), precision)
+                .map_err(WriteError::Body)
+                .fold(
+                    WriteState::new(output, written.clone()),
+                    |mut state, processed| {
+                        state.summary.merge(processed.summary);
+                        state.batch.extend(processed.lines);
+                        if state.batch.len() < state.output.batch_size {
+                            return Either::A(future::ok(state));
+                        }
+                        Either::B(state.flush())
+                    },
+                )
+                .and_then(WriteState::flush)
+                .then(move |result| {
+                    if let Ok(state) = &result {
+                        let summary = &state.summary;
+                        info!(
+                            "Forwarded {} points from {} lines ({} rejected, {} dropped, {} held)",
+                            summary.points,
+                            summary.lines,
+                            summary.rejected,
+                            summary.dropped,
+                            summary.held
+                        );
+                    }
+                    match result {
+                        Ok(ref state) if state.summary.rejected > 0 => {
+                            return future::ok(error_response(
+                                response,
+                                StatusCode::BAD_REQUEST,
+                                state.summary.error_message(),
+                            ));
+                        }
+                        Ok(_) => {
+                            *response.status_mut() = StatusCode::OK;
+                        }
+                        Err(WriteError::Body(ReadError::Body(e))) => {
+                            warn!("Error reading request body: {}", e);
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
+                        Err(WriteError::Body(e)) => {
+                            warn!("Rejected write: {}", e);
+                            // Lines before the one that broke a limit may
+                            // have gone out already.
+                            let written = written.load(Ordering::Relaxed);
+                            if written > 0 {
+                                return future::ok(error_response(
+                                    response,
+                                    StatusCode::BAD_REQUEST,
+                                    format!("partial write: {} ({} points written)", e, written),
+                                ));
+                            }
+                            let status = match e {
+                                ReadError::LineTooLong { .. } => StatusCode::BAD_REQUEST,
+                                _ => StatusCode::PAYLOAD_TOO_LARGE,
+                            };
+                            return future::ok(error_response(response, status, e.to_string()));
+                        }
+                        Err(WriteError::QueueClosed) => {
+                            error!("Output queue closed");
+                            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        }
+                    }
+                    future::ok::<_, hyper::Error>(response)
+                });
 
             return Box::new(mapping);
         }
@@ -63,40 +247,69 @@ fn intercept(req: Request<Body>, processors: Arc<HashMap<String, MetricProcessor
     Box::new(future::ok(response))
 }
 
-fn build_processors(settings: &mut Settings) -> HashMap<String, MetricProcessor> {
-    let mut map = HashMap::new();
-    match &settings.measurements {
-        Some(m) => {
-            for (key, value) in m {
-                println!("Measurement {} goes to {}/{}", key, value.server, value.db);
-                let processor = match &value.strip_tags {
-                    Some(tags) => MetricProcessor::new(tags.clone()),
-                    None => MetricProcessor::new(Vec::new()),
-                };
-                map.insert(key.clone(), processor);
-            }
+fn build_processors(settings: &mut Settings) -> Result<Routes, String> {
+    let mut routes = Routes::default();
+    if let Some(m) = &settings.measurements {
+        // Pattern routes are tried in name order.
+        let mut names: Vec<&String> = m.keys().collect();
+        names.sort();
+        for key in names {
+            let value = &m[key];
+            println!(
+                "Measurement {} goes to {}/{}/{}",
+                value.pattern.as_ref().unwrap_or(key),
+                value.server,
+                value.db,
+                value.rp.as_ref().map_or("default", |rp| rp.as_str())
+            );
+            let processor =
+                MetricProcessor::new(value).map_err(|e| format!("measurement {}: {}", key, e))?;
+            routes.insert(key.clone(), processor);
         }
-        None => {}
     }
-    map
+    Ok(routes)
+}
+
+/// Forwards the points routes have held back, such as aggregates, as they
+/// become due.
+fn flush_held_points(pipeline: Pipeline, output: Output) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(FLUSH_INTERVAL)
+        .map_err(|e| error!("Flush timer failed: {}", e))
+        .fold(output, move |output, _| {
+            pipeline.flush().and_then(|batch| {
+                if batch.is_empty() {
+                    return Either::A(future::ok(output));
+                }
+                Either::B(
+                    output
+                        .send(batch)
+                        .map_err(|_| error!("Output queue closed")),
+                )
+            })
+        })
+        .map(|_| ())
 }
 
-fn args() -> ArgMatches {
+fn args() -> ArgMatches<'static> {
     App::new("Interflux")
         .version("0.1.0")
         .author("Mark Rendle <mark@rendlelabs.com>")
         .about("Pre-processing and routing for InfluxDB metrics")
-        .arg(Arg::with_name("config")
-            .short("c")
-            .long("config")
-            .value_name("FILE")
-            .help("Sets a custom config file")
-            .required(false)
-            .takes_value(true))
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("FILE")
+                .help("Sets a custom config file")
+                .required(false)
+                .takes_value(true),
+        )
         .get_matches()
 }
 
 fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
     let arg_matches = args();
 
     let config_path = arg_matches.value_of("config").unwrap_or("config.toml");
@@ -114,21 +327,43 @@ fn main() {
         }
     }
 
-    let processors = Arc::new(build_processors(&mut settings));
+    let workers = settings.workers.unwrap_or_else(num_cpus::get).max(1);
+    let limits = settings.limits;
+    let routes = match build_processors(&mut settings) {
+        Ok(routes) => routes,
+        Err(err) => {
+            error!("Config error {}", err);
+            return;
+        }
+    };
+    let pipeline = Pipeline::new(routes, workers, limits);
+    let (output, forwarder) = output::channel(
+        settings.queue_size.unwrap_or(64),
+        settings.batch_size.unwrap_or(5000),
+    );
 
     let addr = ([0, 0, 0, 0], 8080).into();
 
+    let service_pipeline = pipeline.clone();
+    let service_output = output.clone();
     let service = move || {
-        let processors = processors.clone();
+        let pipeline = service_pipeline.clone();
+        let output = service_output.clone();
 
-        service_fn(move |req| intercept(req, processors.clone()))
+        service_fn(move |req| intercept(req, pipeline.clone(), output.clone(), limits))
     };
 
     let server = Server::bind(&addr)
         .serve(service)
         .map_err(|e| eprintln!("Server error: {}", e));
 
-    println!("Started http server: 0.0.0.0:8080");
+    println!("Started http server: 0.0.0.0:8080 with {} workers", workers);
+
+    let flusher = flush_held_points(pipeline, output);
 
-    hyper::rt::run(server);
+    hyper::rt::run(future::lazy(move || {
+        hyper::rt::spawn(forwarder);
+        hyper::rt::spawn(flusher);
+        server
+    }));
 }
@@ -0,0 +1,66 @@
+use bytes::Bytes;
+use futures::sync::mpsc;
+use futures::{Future, Sink, Stream};
+
+/// Processed lines, forwarded together.
+pub type Batch = Vec<Bytes>;
+
+/// The output queue is gone, so nothing more can be forwarded.
+#[derive(Debug)]
+pub struct QueueClosed;
+
+/// Sending half of the bounded queue between request handlers and the forwarder.
+///
+/// Each request owns its own `Output`. When the queue is full, `send` doesn't
+/// complete, so the handler stops reading the request body and the client is
+/// pushed back on through TCP flow control.
+#[derive(Clone)]
+pub struct Output {
+    sender: mpsc::Sender<Batch>,
+    pub batch_size: usize,
+}
+
+impl Output {
+    pub fn send(self, batch: Batch) -> impl Future<Item = Output, Error = QueueClosed> {
+        let batch_size = self.batch_size;
+        self.sender
+            .send(batch)
+            .map(move |sender| Output { sender, batch_size })
+            .map_err(|_| QueueClosed)
+    }
+}
+
+/// Creates the output queue, holding at most `capacity` batches, and the
+/// forwarder future that drains it.
+pub fn channel(capacity: usize, batch_size: usize) -> (Output, impl Future<Item = (), Error = ()>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let forwarder = receiver.for_each(|batch: Batch| {
+        for line in batch {
+            println!("Processed: '{}'", String::from_utf8_lossy(&line));
+        }
+        Ok(())
+    });
+    (Output { sender, batch_size }, forwarder)
+}
+
+#[test]
+fn check_send_waits_for_room_in_queue() {
+    use futures::future;
+    use futures::sync::mpsc;
+
+    let (sender, mut receiver) = mpsc::channel(1);
+    let output = Output {
+        sender,
+        batch_size: 1,
+    };
+    future::lazy(move || {
+        let output = output.send(vec![Bytes::from("a v=1\n")]).wait().unwrap();
+        let mut pending = output.send(vec![Bytes::from("b v=2\n")]);
+        assert!(pending.poll().unwrap().is_not_ready());
+        assert!(receiver.poll().unwrap().is_ready());
+        assert!(pending.poll().unwrap().is_ready());
+        Ok::<(), ()>(())
+    })
+    .wait()
+    .unwrap();
+}
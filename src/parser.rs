@@ -1,29 +1,91 @@
 use nom::*;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
 use std::str;
 
-named!(terminator<char>, one_of!(&b" ,\n"[..]));
-named!(until_terminator, take_until_either!(" ,\n")); // take_till!(|ch| ch == b',' || ch == b' ' || ch == b'\n') );
+use crate::point::{FieldValue, PointRef};
+
+/// Raw key/value pairs from a tag set or field set, borrowed from the line.
+pub type Pairs<'a> = Vec<(&'a [u8], &'a [u8])>;
+
+/// Characters that may be backslash-escaped in a measurement name.
+const MEASUREMENT_ESCAPES: &[u8] = b", ";
+/// Characters that may be backslash-escaped in tag keys, tag values and field keys.
+const KEY_ESCAPES: &[u8] = b",= ";
+
+/// Takes everything up to the first unescaped byte in `terminators`.
+/// A backslash always escapes the byte that follows it, matching the way
+/// InfluxDB scans line protocol, and the end of the input counts as a terminator.
+fn escaped_until<'a>(input: &'a [u8], terminators: &[u8]) -> IResult<&'a [u8], &'a [u8]> {
+    let mut i = 0;
+    while i < input.len() {
+        let c = input[i];
+        if c == b'\\' {
+            i += 2;
+            continue;
+        }
+        if terminators.contains(&c) {
+            return Ok((&input[i..], &input[..i]));
+        }
+        i += 1;
+    }
+    Ok((&input[input.len()..], input))
+}
+
+/// Matches a single delimiter byte, failing (rather than asking for more) at end of input.
+fn byte(input: &[u8], expected: u8) -> IResult<&[u8], u8> {
+    match input.first() {
+        Some(&c) if c == expected => Ok((&input[1..], c)),
+        _ => Err(Err::Error(error_position!(input, ErrorKind::Char))),
+    }
+}
+
+/// Matches a double-quoted string field value, including the quotes.
+/// Inside the quotes `\"` and `\\` are escapes, so commas, spaces and
+/// equals signs are all part of the value.
+fn quoted_string(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    if input.first() != Some(&b'"') {
+        return Err(Err::Error(error_position!(input, ErrorKind::Char)));
+    }
+    let mut i = 1;
+    while i < input.len() {
+        match input[i] {
+            b'\\' => i += 2,
+            b'"' => return Ok((&input[i + 1..], &input[..=i])),
+            _ => i += 1,
+        }
+    }
+    Err(Err::Error(error_position!(input, ErrorKind::Char)))
+}
+
+fn field_value(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    if input.first() == Some(&b'"') {
+        quoted_string(input)
+    } else {
+        escaped_until(input, b", \n")
+    }
+}
+
+named!(until_terminator, call!(escaped_until, b" ,\n"));
 
 named!(
-    delimiter_to_equal_sign,
-    preceded!(one_of!(&b" ,"[..]), take_until_and_consume!("="))
+    key,
+    terminated!(call!(escaped_until, b"=, \n"), call!(byte, b'='))
 );
 
 named!(
-    space_to_equal_sign,
-    preceded!(tag!(" "), take_until_and_consume!("="))
+    delimiter_to_equal_sign,
+    preceded!(alt!(call!(byte, b' ') | call!(byte, b',')), key)
 );
 
-named!(field_name, take_until_and_consume!("="));
+named!(space_to_equal_sign, preceded!(call!(byte, b' '), key));
 
-named!(field_value, take_until_either!(", \n"));
+named!(field_name, call!(key));
 
-named!(
-    comma_to_equal_sign,
-    preceded!(tag!(","), take_until_and_consume!("="))
-);
+named!(comma_to_equal_sign, preceded!(call!(byte, b','), key));
 
-named!(measurement, take_until_either!(" ,"));
+named!(measurement, call!(escaped_until, b" ,\n"));
 
 named!( tag<&[u8], (&[u8], &[u8])>,
     pair!(
@@ -39,20 +101,20 @@ named!( tags<&[u8], Vec<(&[u8],&[u8])> >,
 named!( first_field<&[u8], (&[u8], &[u8])>,
     pair!(
         space_to_equal_sign,
-        until_terminator
+        field_value
     )
 );
 
 named!( other_field<&[u8], (&[u8], &[u8])>,
     pair!(
         comma_to_equal_sign,
-        until_terminator
+        field_value
     )
 );
 
 named!( field<&[u8], (&[u8], &[u8])>,
     preceded!(
-        opt!(tag!(",")),
+        opt!(call!(byte, b',')),
         pair!(
             field_name,
             field_value
@@ -64,80 +126,201 @@ named!( fields<&[u8], Vec<(&[u8],&[u8])> >,
     do_parse!(
         first: first_field >>
         others: many0!( other_field ) >>
-        ( combine_fields(first, others) )
+        ({
+            let mut v = Vec::with_capacity(others.len() + 1);
+            v.push(first);
+            v.extend(others);
+            v
+        })
     )
 );
 
-named!( timestamp<&[u8], Option<&[u8]> >,
-    opt!(
-        preceded!(tag!(" "), digit0)
+named!( metric<&[u8], PointRef<'_>>,
+    do_parse!(
+        measurement: measurement >>
+        tags: tags >>
+        fields: fields >>
+        timestamp: get_timestamp >>
+        ( PointRef { measurement, tags, fields, timestamp } )
     )
 );
 
-fn combine_fields<'a>(
-    first: (&'a [u8], &'a [u8]),
-    others: Vec<(&'a [u8], &'a [u8])>,
-) -> Vec<(&'a [u8], &'a [u8])> {
-    let mut v: Vec<(&[u8], &[u8])> = Vec::with_capacity(others.len() + 1);
-    v.push(first);
-    v.extend(&others);
-    v
+/// Takes the optional timestamp at the end of a line.
+/// A lone space with nothing after it is an empty (and therefore invalid) timestamp.
+fn get_timestamp(input: &[u8]) -> IResult<&[u8], Option<&[u8]>> {
+    match input.first() {
+        Some(b' ') => {
+            let (remaining, t) = escaped_until(&input[1..], b" \n")?;
+            Ok((remaining, Some(t)))
+        }
+        _ => Ok((input, None)),
+    }
 }
 
-fn get_timestamp(input: &[u8]) -> IResult<&[u8], Option<&[u8]>> {
-    if input.len() == 0 {
-        return Ok((input, None));
+/// Removes backslash escapes for the given set of escapable characters.
+/// Backslashes before any other byte are literal and kept as-is.
+fn unescape_with<'a>(bytes: &'a [u8], escapable: &[u8]) -> Cow<'a, [u8]> {
+    if !bytes.contains(&b'\\') {
+        return Cow::Borrowed(bytes);
     }
-    if input[0] == b'\n' {
-        return Ok((&input[1..], None));
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 1 < bytes.len() && escapable.contains(&bytes[i + 1]) {
+            out.push(bytes[i + 1]);
+            i += 2;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
     }
+    Cow::Owned(out)
+}
 
-    let r = timestamp(input);
-    match r {
-        Ok((remaining, value)) => Ok((remaining, value)),
-        Err(Err::Incomplete(_)) => Ok((input, Some(&input[1..]))),
-        Err(e) => Err(e),
-    }
+/// Unescapes a raw measurement name as it appears on the wire.
+pub fn unescape_measurement(bytes: &[u8]) -> Cow<'_, [u8]> {
+    unescape_with(bytes, MEASUREMENT_ESCAPES)
 }
 
-pub fn get_measurement_name(bytes: &[u8]) -> Option<(&[u8], &str)> {
-    match measurement(&bytes) {
-        Ok((r, m)) => match str::from_utf8(m) {
-            Ok(s) => Some((r, s)),
-            Err(_) => None,
-        },
-        Err(Err::Incomplete(_needed)) => None,
-        Err(Err::Error(_e)) => None,
-        Err(Err::Failure(_e)) => None,
-    }
+/// Unescapes a raw tag key, tag value or field key as it appears on the wire.
+pub fn unescape_key(bytes: &[u8]) -> Cow<'_, [u8]> {
+    unescape_with(bytes, KEY_ESCAPES)
+}
+
+/// Why a line could not be parsed, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// 1-based line number within the request body.
+    pub line: usize,
+    /// 1-based byte offset within the line.
+    pub column: usize,
+    pub reason: &'static str,
 }
 
-pub fn parse_tags(bytes: &[u8]) -> Option<(&[u8], Vec<(&[u8], &[u8])>)> {
-    match tags(bytes) {
-        Ok((r, t)) => Some((r, t)),
-        Err(Err::Incomplete(_needed)) => None,
-        Err(Err::Error(_e)) => None,
-        Err(Err::Failure(_e)) => None,
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.reason
+        )
     }
 }
 
-pub fn parse_fields(bytes: &[u8]) -> Option<(&[u8], Vec<(&[u8], &[u8])>)> {
-    match fields(bytes) {
-        Ok((r, t)) => Some((r, t)),
-        Err(Err::Incomplete(_needed)) => None,
-        Err(Err::Error(_e)) => None,
-        Err(Err::Failure(_e)) => None,
+/// The part of a line that holds the point: leading spaces and tabs are
+/// skipped and a trailing `\n` or `\r\n` is cut off.
+pub fn content_range(bytes: &[u8]) -> Range<usize> {
+    let start = bytes
+        .iter()
+        .position(|&c| c != b' ' && c != b'\t')
+        .unwrap_or(bytes.len());
+    let mut end = bytes.len();
+    if bytes[start..end].ends_with(b"\n") {
+        end -= 1;
     }
+    if bytes[start..end].ends_with(b"\r") {
+        end -= 1;
+    }
+    start..end
+}
+
+/// True for lines that carry no point: blank or whitespace-only lines and
+/// `#` comments.
+pub fn is_ignored(bytes: &[u8]) -> bool {
+    let content = &bytes[content_range(bytes)];
+    content.first() == Some(&b'#')
+        || content
+            .iter()
+            .all(|&c| c == b' ' || c == b'\t' || c == b'\r')
 }
 
-pub fn parse_timestamp(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
-    match get_timestamp(bytes) {
-        Ok((remaining, timestamp)) => match timestamp {
-            None => None,
-            Some(t) => Some((remaining, t)),
-        },
-        Err(_) => None,
+/// Parses and validates a whole line into a `PointRef` borrowing from `bytes`.
+/// `line` is only used to locate errors. Leading whitespace and a `\n` or
+/// `\r\n` ending are allowed. Every slice of an accepted point is valid UTF-8.
+pub fn parse_point(bytes: &[u8], line: usize) -> Result<PointRef<'_>, ParseError> {
+    let Range { start, end } = content_range(bytes);
+    let error = |at: &[u8], reason| ParseError {
+        line,
+        column: end - at.len() + 1,
+        reason,
+    };
+    let bytes = &bytes[start..end];
+
+    if let Err(e) = str::from_utf8(bytes) {
+        return Err(error(&bytes[e.valid_up_to()..], "invalid UTF-8"));
+    }
+    // Points are written back out one per line, so a line break can't be
+    // part of one, escaped or quoted.
+    if let Some(at) = bytes.iter().position(|&c| c == b'\n' || c == b'\r') {
+        return Err(error(&bytes[at..], "line break within line"));
+    }
+
+    let (mut rest, measurement) =
+        measurement(bytes).map_err(|_| error(bytes, "invalid measurement"))?;
+    if measurement.is_empty() {
+        return Err(error(bytes, "missing measurement"));
+    }
+
+    let mut tags = Vec::new();
+    while rest.first() == Some(&b',') {
+        let (remaining, (key, value)) =
+            tag(rest).map_err(|_| error(rest, "invalid tag, expected key=value"))?;
+        if key.is_empty() {
+            return Err(error(&rest[1..], "missing tag key"));
+        }
+        if value.is_empty() {
+            return Err(error(remaining, "missing tag value"));
+        }
+        tags.push((key, value));
+        rest = remaining;
+    }
+
+    if rest.first() != Some(&b' ') || rest.len() == 1 {
+        return Err(error(rest, "missing field set"));
+    }
+    let mut fields = Vec::new();
+    let mut delimiter = b' ';
+    while rest.first() == Some(&delimiter) {
+        let at = &rest[1..];
+        let (remaining, key) =
+            key(at).map_err(|_| error(at, "invalid field, expected key=value"))?;
+        if key.is_empty() {
+            return Err(error(at, "missing field key"));
+        }
+        let at = remaining;
+        let (remaining, value) =
+            field_value(at).map_err(|_| error(at, "unterminated string literal"))?;
+        if value.is_empty() {
+            return Err(error(at, "missing field value"));
+        }
+        if value[0] != b'"' {
+            FieldValue::parse(value).map_err(|reason| error(at, reason))?;
+        }
+        fields.push((key, value));
+        rest = remaining;
+        delimiter = b',';
+    }
+
+    let at = rest;
+    let (rest, timestamp) = get_timestamp(at).map_err(|_| error(at, "invalid timestamp"))?;
+    if let Some(t) = timestamp {
+        if str::from_utf8(t)
+            .ok()
+            .and_then(|t| t.parse::<i64>().ok())
+            .is_none()
+        {
+            return Err(error(&at[1..], "invalid timestamp"));
+        }
+    }
+    if !rest.is_empty() {
+        return Err(error(rest, "unexpected data after timestamp"));
     }
+    Ok(PointRef {
+        measurement,
+        tags,
+        fields,
+        timestamp,
+    })
 }
 
 #[test]
@@ -379,3 +562,326 @@ fn check_metric() {
         Err(Err::Failure(e)) => panic!("Failure: {:?}", e),
     }
 }
+
+#[test]
+fn check_measurement_with_escaped_comma_and_space() {
+    let t = b"my\\ requests\\,total,method=GET count=1\n";
+    let r = measurement(t);
+    match r {
+        Ok((remaining, measurement)) => {
+            assert_eq!(measurement, &b"my\\ requests\\,total"[..]);
+            assert_eq!(remaining, b",method=GET count=1\n");
+            assert_eq!(&*unescape_measurement(measurement), b"my requests,total");
+        }
+        Err(Err::Incomplete(needed)) => panic!("Incomplete: {:?}", needed),
+        Err(Err::Error(e)) => panic!("Error: {:?}", e),
+        Err(Err::Failure(e)) => panic!("Failure: {:?}", e),
+    };
+}
+
+#[test]
+fn check_tag_with_escaped_characters() {
+    let t = b",host\\ name=web\\,01\\=a count=1";
+    let r = tag(t);
+    match r {
+        Ok((remaining, (key, value))) => {
+            assert_eq!(key, &b"host\\ name"[..]);
+            assert_eq!(value, &b"web\\,01\\=a"[..]);
+            assert_eq!(remaining, b" count=1");
+            assert_eq!(&*unescape_key(key), b"host name");
+            assert_eq!(&*unescape_key(value), b"web,01=a");
+        }
+        Err(Err::Incomplete(needed)) => panic!("Incomplete: {:?}", needed),
+        Err(Err::Error(e)) => panic!("Error: {:?}", e),
+        Err(Err::Failure(e)) => panic!("Failure: {:?}", e),
+    };
+}
+
+#[test]
+fn check_fields_with_quoted_string() {
+    let t = b" msg=\"a, b=c \\\"x\\\"\",count=1i 1234567890\n";
+    let r = fields(t);
+    match r {
+        Ok((remaining, vec)) => {
+            assert_eq!(vec.len(), 2);
+            let (key, value) = vec[0];
+            assert_eq!(key, b"msg");
+            assert_eq!(value, &b"\"a, b=c \\\"x\\\"\""[..]);
+            let (key, value) = vec[1];
+            assert_eq!(key, b"count");
+            assert_eq!(value, b"1i");
+            assert_eq!(remaining, b" 1234567890\n");
+        }
+        Err(Err::Incomplete(needed)) => panic!("Incomplete: {:?}", needed),
+        Err(Err::Error(e)) => panic!("Error: {:?}", e),
+        Err(Err::Failure(e)) => panic!("Failure: {:?}", e),
+    };
+}
+
+#[test]
+fn check_field_with_unterminated_quoted_string() {
+    let t = b"msg=\"abc";
+    assert!(field(t).is_err());
+}
+
+#[test]
+fn check_unescape_leaves_other_backslashes() {
+    assert_eq!(&*unescape_key(b"C:\\temp\\ dir"), b"C:\\temp dir");
+    assert_eq!(&*unescape_measurement(b"a\\=b"), b"a\\=b");
+}
+
+#[test]
+fn check_escaped_point_round_trip() {
+    let t = b"my\\ requests,host\\ name=web\\,01 msg=\"a, b=c \\\"x\\\"\",count=1i 1234567890\n";
+    let point = parse_point(t, 1).unwrap().to_point().unwrap();
+    assert_eq!(point.measurement, "my requests");
+    assert_eq!(
+        point.tags[0],
+        (String::from("host name"), String::from("web,01"))
+    );
+    assert_eq!(&point.to_line()[..], &t[..]);
+}
+
+#[test]
+fn check_parse_errors_locate_the_problem() {
+    let cases: Vec<(&[u8], usize, &str)> = vec![
+        (b"requests\n", 9, "missing field set"),
+        (b"requests,method=GET\n", 20, "missing field set"),
+        (b",method=GET count=1\n", 1, "missing measurement"),
+        (
+            b"requests,method count=1\n",
+            9,
+            "invalid tag, expected key=value",
+        ),
+        (b"requests,=GET count=1\n", 10, "missing tag key"),
+        (b"requests,method= count=1\n", 17, "missing tag value"),
+        (b"requests count=1.5i\n", 16, "invalid integer literal"),
+        (b"requests count=1,=2\n", 18, "missing field key"),
+        (b"requests count=\n", 16, "missing field value"),
+        (b"requests msg=\"abc\n", 14, "unterminated string literal"),
+        (b"requests count=1 12ab\n", 18, "invalid timestamp"),
+        (
+            b"requests count=1 12 x\n",
+            20,
+            "unexpected data after timestamp",
+        ),
+    ];
+    for (line, column, reason) in cases {
+        let expected = ParseError {
+            line: 3,
+            column,
+            reason,
+        };
+        assert_eq!(parse_point(line, 3), Err(expected));
+    }
+}
+
+#[test]
+fn check_invalid_utf8_is_rejected() {
+    let cases: Vec<(&[u8], usize)> = vec![
+        (b"\xff count=1\n", 1),
+        (b"requests,m\xc3=GET count=1\n", 11),
+        (b"requests,method=GET count=1,msg=\"\xe2\x82\" 10\n", 34),
+    ];
+    for (line, column) in cases {
+        let expected = ParseError {
+            line: 1,
+            column,
+            reason: "invalid UTF-8",
+        };
+        assert_eq!(parse_point(line, 1), Err(expected));
+    }
+}
+
+#[test]
+fn check_crlf_and_leading_whitespace() {
+    let expected = parse_point(b"cpu,host=a usage=1 10\n", 1).unwrap();
+    assert_eq!(
+        parse_point(b"cpu,host=a usage=1 10\r\n", 1),
+        Ok(expected.clone())
+    );
+    assert_eq!(parse_point(b" \tcpu,host=a usage=1 10", 1), Ok(expected));
+    assert_eq!(
+        parse_point(b"  requests count=1 12ab\r\n", 1)
+            .unwrap_err()
+            .column,
+        20
+    );
+    assert!(parse_point(b"cpu usage=1 10\r\r\n", 1).is_err());
+}
+
+#[test]
+fn check_ignored_lines() {
+    for line in &[
+        &b""[..],
+        b"\n",
+        b"\r\n",
+        b"  \t\r\n",
+        b"# comment\n",
+        b"  #cpu usage=1\r\n",
+    ] {
+        assert!(is_ignored(line), "{:?}", line);
+    }
+    for line in &[&b"cpu usage=1\n"[..], b" cpu usage=1", b"cpu#1 usage=1\r\n"] {
+        assert!(!is_ignored(line), "{:?}", line);
+    }
+}
+
+/// Inputs that once broke, or nearly broke, the parser.
+#[cfg(test)]
+const MALFORMED: &[&[u8]] = &[
+    b"",
+    b"\\",
+    b"m\\",
+    b"m,\\",
+    b"m,a\\",
+    b"m,a=\\",
+    b"m v=\"\\",
+    b"m v=\"\\\"",
+    b"m v=\"",
+    b" ",
+    b",",
+    b"=",
+    b"m ",
+    b"m  ",
+    b"m ,",
+    b"m =",
+    b"m v",
+    b"m v=",
+    b"m v=1 ",
+    b"m v=1  ",
+    b"m v=1 -",
+    b"m v=-i",
+    b"m v=u",
+    b"m v=i",
+    b"m v=.",
+    b"m v=e",
+    b"m v=1,",
+    b"m v=1,,",
+    b"m,,a=b v=1",
+    b"m,a=b, v=1",
+    b"m v=1 99999999999999999999",
+    b"m v=99999999999999999999i",
+    b"m v=1e999",
+    b"\xff",
+    b"m,\xff=\xfe v=1",
+    b"m v=\"\xc3\"",
+    b"m,t=a\\\nb v=1",
+    b"m s=\"a\rb\"",
+];
+
+#[test]
+fn check_malformed_input_never_panics() {
+    for input in MALFORMED {
+        assert!(parse_point(input, 1).is_err(), "accepted {:?}", input);
+    }
+
+    // Byte-level mutations of valid lines, from a fixed-seed generator.
+    let valid: &[&[u8]] = &[
+        b"cpu,host=a\\ b,region=us\\,west usage=0.5,idle=12i,ok=t 1500000000\n",
+        b"log msg=\"a, b=c \\\"x\\\"\",level=\"warn\" 1\n",
+    ];
+    let mut seed: u32 = 0x2545_f491;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as usize
+    };
+    let alphabet = b" ,=\\\"\nit.-0\xff";
+    for _ in 0..20_000 {
+        let mut line = valid[next() % valid.len()].to_vec();
+        for _ in 0..1 + next() % 4 {
+            let at = next() % line.len();
+            match next() % 3 {
+                0 => line[at] = alphabet[next() % alphabet.len()],
+                1 => {
+                    line.remove(at);
+                }
+                _ => line.insert(at, alphabet[next() % alphabet.len()]),
+            }
+            if line.is_empty() {
+                break;
+            }
+        }
+        if let Ok(point) = parse_point(&line, 1) {
+            let point = point.to_point().expect("accepted point must convert");
+            let reparsed = point.to_line();
+            let again = parse_point(&reparsed, 1).expect("serialized point must parse");
+            assert_eq!(again.to_point(), Some(point));
+        }
+    }
+}
+
+#[test]
+fn check_arbitrary_points_round_trip() {
+    use crate::point::Point;
+
+    let mut seed: u32 = 0x9e37_79b9;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as usize
+    };
+    let alphabet = b"ab ,=\\\"#\n\r";
+    let name = |next: &mut dyn FnMut() -> usize| {
+        let mut name = String::from("x");
+        for _ in 0..next() % 6 {
+            name.push(alphabet[next() % alphabet.len()] as char);
+        }
+        name
+    };
+    for _ in 0..20_000 {
+        let mut point = Point {
+            measurement: name(&mut next),
+            tags: Vec::new(),
+            fields: Vec::new(),
+            timestamp: if next() % 2 == 0 {
+                Some(next() as i64 - 1_000)
+            } else {
+                None
+            },
+        };
+        for _ in 0..next() % 3 {
+            let tag = (name(&mut next), name(&mut next));
+            point.tags.push(tag);
+        }
+        for _ in 0..1 + next() % 3 {
+            let value = match next() % 5 {
+                0 => FieldValue::Float((next() % 1000) as f64 / 8.0),
+                1 => FieldValue::Integer(next() as i64 - 1_000),
+                2 => FieldValue::UInteger(next() as u64),
+                3 => FieldValue::Boolean(next() % 2 == 0),
+                _ => FieldValue::String(name(&mut next)),
+            };
+            let field = (name(&mut next), value);
+            point.fields.push(field);
+        }
+        let line = point.to_line();
+        let parsed = parse_point(&line, 1)
+            .unwrap_or_else(|e| panic!("{:?} serialized as unparseable {:?}: {}", point, line, e))
+            .to_point()
+            .expect("serialized point must convert");
+        assert_eq!(parsed.to_line(), line);
+        // Only line breaks, and backslashes that can't be written, are lost.
+        let stripped = |s: &str| s.replace(&['\\', '\n', '\r'][..], "");
+        assert_eq!(stripped(&parsed.measurement), stripped(&point.measurement));
+        for (parsed, tag) in parsed.tags.iter().zip(&point.tags) {
+            assert_eq!(stripped(&parsed.0), stripped(&tag.0));
+            assert_eq!(stripped(&parsed.1), stripped(&tag.1));
+        }
+        assert_eq!(parsed.tags.len(), point.tags.len());
+        assert_eq!(parsed.fields.len(), point.fields.len());
+        for (parsed, field) in parsed.fields.iter().zip(&point.fields) {
+            assert_eq!(stripped(&parsed.0), stripped(&field.0));
+            match (&parsed.1, &field.1) {
+                (FieldValue::String(parsed), FieldValue::String(s)) => {
+                    assert_eq!(*parsed, s.replace(&['\n', '\r'][..], ""))
+                }
+                (parsed, value) => assert_eq!(parsed, value),
+            }
+        }
+        assert_eq!(parsed.timestamp, point.timestamp);
+    }
+}
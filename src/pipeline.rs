@@ -0,0 +1,492 @@
+use bytes::buf::BufMut;
+use bytes::{Bytes, BytesMut};
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use log::{error, warn};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use crate::parser::{content_range, is_ignored, parse_point, unescape_measurement, ParseError};
+use crate::point::{now_nanos, Point};
+use crate::processors::{Action, MetricProcessor, Routes};
+use crate::settings::Limits;
+
+/// Lines handed to a worker at a time.
+const CHUNK_LINES: usize = 1000;
+
+/// Most parse errors a single write response will describe; the rest are only counted.
+const MAX_REPORTED_ERRORS: usize = 10;
+
+/// What happened to the lines of a write request, or of a chunk of one.
+#[derive(Default)]
+pub struct WriteSummary {
+    pub lines: usize,
+    /// Points forwarded to the output.
+    pub points: usize,
+    pub rejected: usize,
+    /// Valid points a route chose not to forward.
+    pub dropped: usize,
+    /// Points kept back to be forwarded later, as part of an aggregate.
+    pub held: usize,
+    pub errors: Vec<ParseError>,
+}
+
+impl WriteSummary {
+    fn reject(&mut self, error: ParseError) {
+        warn!("Rejected {}", error);
+        self.rejected += 1;
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(error);
+        }
+    }
+
+    /// Adds the counts and errors of a later chunk.
+    pub fn merge(&mut self, other: WriteSummary) {
+        self.lines += other.lines;
+        self.points += other.points;
+        self.rejected += other.rejected;
+        self.dropped += other.dropped;
+        self.held += other.held;
+        let room = MAX_REPORTED_ERRORS - self.errors.len();
+        self.errors.extend(other.errors.into_iter().take(room));
+    }
+
+    /// Describes the rejected lines for the error response.
+    pub fn error_message(&self) -> String {
+        let mut message = String::from("partial write: unable to parse ");
+        let described: Vec<String> = self.errors.iter().map(|e| e.to_string()).collect();
+        message.push_str(&described.join("; "));
+        if self.rejected > self.errors.len() {
+            message.push_str(&format!(
+                " (and {} more)",
+                self.rejected - self.errors.len()
+            ));
+        }
+        message
+    }
+}
+
+/// What became of a line that parsed.
+enum Outcome {
+    Forward(Bytes),
+    /// A point still to go through the stateful steps of its route.
+    Update(Point, Arc<MetricProcessor>),
+    Drop,
+    Hold,
+    /// No route handles the measurement.
+    Unrouted,
+}
+
+/// A line of a chunk once it's been parsed and transformed.
+enum Staged {
+    Line(Bytes),
+    /// A point for `MetricProcessor::update`, with its line number.
+    Point(Point, Arc<MetricProcessor>, usize),
+}
+
+/// One chunk of lines after the parallel stage, in input order.
+struct Parsed {
+    staged: Vec<Staged>,
+    summary: WriteSummary,
+}
+
+/// The output of one chunk of lines, in input order.
+pub struct Processed {
+    pub lines: Vec<Bytes>,
+    pub summary: WriteSummary,
+}
+
+/// Parses and processes chunks of lines on a pool of worker threads,
+/// keeping that work off the HTTP reactor.
+#[derive(Clone)]
+pub struct Pipeline {
+    processors: Arc<Routes>,
+    pool: CpuPool,
+    workers: usize,
+    limits: Limits,
+}
+
+impl Pipeline {
+    pub fn new(processors: Routes, workers: usize, limits: Limits) -> Self {
+        Pipeline {
+            processors: Arc::new(processors),
+            pool: CpuPool::new(workers),
+            workers,
+            limits,
+        }
+    }
+
+    /// Takes the points routes have held back and that are now due,
+    /// serialized as lines.
+    pub fn flush(&self) -> impl Future<Item = Vec<Bytes>, Error = ()> {
+        let processors = self.processors.clone();
+        self.pool.spawn_fn(move || {
+            let points = panic::catch_unwind(AssertUnwindSafe(|| processors.flush(now_nanos())))
+                .unwrap_or_else(|_| {
+                    error!("Flushing held points panicked");
+                    Vec::new()
+                });
+            Ok(points.iter().map(Point::to_line).collect())
+        })
+    }
+
+    /// Splits a stream of lines into chunks and parses and transforms them
+    /// in parallel. The stateful steps of routes, such as rates and dedup,
+    /// then run on one chunk at a time, in the order the chunks were read,
+    /// so they see the points of a series in the order they were written.
+    /// Chunks are yielded in that order too. Timestamps are in units of
+    /// `precision` nanoseconds, and are forwarded in nanoseconds.
+    pub fn process<S>(
+        &self,
+        lines: S,
+        precision: i64,
+    ) -> impl Stream<Item = Processed, Error = S::Error>
+    where
+        S: Stream<Item = Bytes>,
+        S::Error: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let updates = self.pool.clone();
+        let processors = self.processors.clone();
+        let limits = self.limits;
+        let mut next_line = 1;
+        lines
+            .chunks(CHUNK_LINES)
+            .map(move |chunk| {
+                let first_line = next_line;
+                next_line += chunk.len();
+                let processors = processors.clone();
+                pool.spawn_fn(move || {
+                    Ok(parse_chunk(
+                        &chunk,
+                        first_line,
+                        precision,
+                        &processors,
+                        &limits,
+                    ))
+                })
+            })
+            .buffered(self.workers)
+            .map(move |parsed| updates.spawn_fn(move || Ok(update_chunk(parsed))))
+            .buffered(1)
+    }
+}
+
+fn parse_chunk(
+    chunk: &[Bytes],
+    first_line: usize,
+    precision: i64,
+    processors: &Routes,
+    limits: &Limits,
+) -> Parsed {
+    let mut parsed = Parsed {
+        staged: Vec::with_capacity(chunk.len()),
+        summary: WriteSummary::default(),
+    };
+    for (i, buf) in chunk.iter().enumerate() {
+        parsed.summary.lines += 1;
+        if is_ignored(buf) {
+            continue;
+        }
+        let line = first_line + i;
+        // A bug in a processor must cost one line, not the connection.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            run(buf, line, precision, processors, limits)
+        }))
+        .unwrap_or_else(|_| {
+            error!("Processing panicked on line {}", line);
+            Err(ParseError {
+                line,
+                column: 1,
+                reason: "internal error processing line",
+            })
+        });
+        match result {
+            Ok(Outcome::Forward(line)) => parsed.staged.push(Staged::Line(line)),
+            Ok(Outcome::Update(point, processor)) => {
+                parsed.staged.push(Staged::Point(point, processor, line))
+            }
+            Ok(Outcome::Drop) => parsed.summary.dropped += 1,
+            Ok(Outcome::Hold) => parsed.summary.held += 1,
+            Ok(Outcome::Unrouted) => {}
+            Err(error) => parsed.summary.reject(error),
+        }
+    }
+    parsed
+}
+
+/// Runs the stateful steps on the points of a parsed chunk.
+fn update_chunk(parsed: Parsed) -> Processed {
+    let mut processed = Processed {
+        lines: Vec::with_capacity(parsed.staged.len()),
+        summary: parsed.summary,
+    };
+    for staged in parsed.staged {
+        let (mut point, processor, line) = match staged {
+            Staged::Line(line) => {
+                processed.summary.points += 1;
+                processed.lines.push(line);
+                continue;
+            }
+            Staged::Point(point, processor, line) => (point, processor, line),
+        };
+        let action = panic::catch_unwind(AssertUnwindSafe(|| processor.update(&mut point)));
+        match action {
+            Ok(Action::Forward) => {
+                processed.summary.points += 1;
+                processed.lines.push(point.to_line());
+            }
+            Ok(Action::Drop) => processed.summary.dropped += 1,
+            Ok(Action::Hold) => processed.summary.held += 1,
+            Err(_) => {
+                error!("Processing panicked on line {}", line);
+                processed.summary.reject(ParseError {
+                    line,
+                    column: 1,
+                    reason: "internal error processing line",
+                });
+            }
+        }
+    }
+    processed
+}
+
+/// Returns the point as received, ending in a single `\n`. The line is sliced
+/// without copying unless its ending has to be fixed up.
+fn terminated(buf: &Bytes) -> Bytes {
+    let content = content_range(buf);
+    if buf.len() == content.end + 1 && buf[content.end] == b'\n' {
+        return buf.slice(content.start, buf.len());
+    }
+    let mut line = BytesMut::with_capacity(content.len() + 1);
+    line.extend_from_slice(&buf[content]);
+    line.put(b'\n');
+    line.freeze()
+}
+
+fn run(
+    buf: &Bytes,
+    line: usize,
+    precision: i64,
+    processors: &Routes,
+    limits: &Limits,
+) -> Result<Outcome, ParseError> {
+    let point = parse_point(buf, line)?;
+    if point.tags.len() > limits.max_tags {
+        return Err(ParseError {
+            line,
+            column: 1,
+            reason: "too many tags",
+        });
+    }
+    if point.fields.len() > limits.max_fields {
+        return Err(ParseError {
+            line,
+            column: 1,
+            reason: "too many fields",
+        });
+    }
+    let name = unescape_measurement(point.measurement);
+    let processor = match std::str::from_utf8(&name)
+        .ok()
+        .and_then(|n| processors.get(n))
+    {
+        Some(processor) => processor,
+        None => return Ok(Outcome::Unrouted),
+    };
+    if precision == 1 && processor.passes_through(&point) {
+        return Ok(Outcome::Forward(terminated(buf)));
+    }
+    let mut point = point.to_point().ok_or(ParseError {
+        line,
+        column: 1,
+        reason: "invalid point",
+    })?;
+    if let Some(timestamp) = point.timestamp {
+        let timestamp = timestamp.checked_mul(precision).ok_or(ParseError {
+            line,
+            column: 1,
+            reason: "timestamp out of range",
+        })?;
+        point.timestamp = Some(timestamp);
+    }
+    Ok(match processor.transform(&mut point) {
+        Action::Forward if processor.is_stateful() => Outcome::Update(point, processor.clone()),
+        Action::Forward => Outcome::Forward(point.to_line()),
+        Action::Drop => Outcome::Drop,
+        Action::Hold => Outcome::Hold,
+    })
+}
+
+#[test]
+fn check_parallel_chunks_keep_line_order() {
+    use crate::processors::MetricProcessor;
+    use crate::settings::Measurement;
+
+    let mut processors = Routes::default();
+    processors.insert(
+        String::from("m"),
+        MetricProcessor::new(&Measurement::default()).unwrap(),
+    );
+    let pipeline = Pipeline::new(processors, 4, Limits::default());
+
+    let input: Vec<Bytes> = (0..CHUNK_LINES * 5 + 7)
+        .map(|i| Bytes::from(format!("m,host=h{} v={}i\n", i % 3, i)))
+        .collect();
+    let lines = futures::stream::iter_ok::<_, ()>(input.clone());
+    let output: Vec<Bytes> = pipeline
+        .process(lines, 1)
+        .collect()
+        .wait()
+        .unwrap()
+        .into_iter()
+        .flat_map(|processed| processed.lines)
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn check_stateful_steps_see_series_in_order() {
+    use crate::processors::MetricProcessor;
+    use crate::settings::{Measurement, Rates};
+
+    let mut processors = Routes::default();
+    processors.insert(
+        String::from("net"),
+        MetricProcessor::new(&Measurement {
+            rates: Some(Rates {
+                fields: vec![String::from("bytes")],
+                ..Rates::default()
+            }),
+            ..Measurement::default()
+        })
+        .unwrap(),
+    );
+    let pipeline = Pipeline::new(processors, 4, Limits::default());
+
+    let input: Vec<Bytes> = (0..CHUNK_LINES * 4)
+        .map(|i| {
+            let second = i as i64 / 2 + 1;
+            let line = format!(
+                "net,if=e{} bytes={}i {}\n",
+                i % 2,
+                second * 10,
+                second * 1_000_000_000
+            );
+            Bytes::from(line)
+        })
+        .collect();
+    let lines = futures::stream::iter_ok::<_, ()>(input);
+    let mut summary = WriteSummary::default();
+    let mut output = Vec::new();
+    for processed in pipeline.process(lines, 1).collect().wait().unwrap() {
+        summary.merge(processed.summary);
+        output.extend(processed.lines);
+    }
+    // Only the first point of each series has no rate.
+    assert_eq!(summary.dropped, 2);
+    assert_eq!(summary.points, CHUNK_LINES * 4 - 2);
+    assert_eq!(
+        output[0],
+        Bytes::from(&b"net,if=e0 bytes=20i,bytes_rate=10 2000000000\n"[..])
+    );
+    let rate = b",bytes_rate=10 ";
+    assert!(output
+        .iter()
+        .all(|line| line.windows(rate.len()).any(|w| w == rate)));
+}
+
+#[test]
+fn check_timestamps_are_converted_to_nanoseconds() {
+    use crate::processors::MetricProcessor;
+    use crate::settings::Measurement;
+
+    let mut processors = Routes::default();
+    processors.insert(
+        String::from("m"),
+        MetricProcessor::new(&Measurement::default()).unwrap(),
+    );
+    let chunk: Vec<Bytes> = vec![
+        Bytes::from(&b"m v=1i 5"[..]),
+        Bytes::from(&b"m v=2i"[..]),
+        Bytes::from(&b"m v=3i 9223372036854775807"[..]),
+    ];
+    let processed = update_chunk(parse_chunk(
+        &chunk,
+        1,
+        1_000_000_000,
+        &processors,
+        &Limits::default(),
+    ));
+    assert_eq!(
+        processed.lines,
+        vec![
+            Bytes::from(&b"m v=1i 5000000000\n"[..]),
+            Bytes::from(&b"m v=2i\n"[..]),
+        ]
+    );
+    assert_eq!(processed.summary.rejected, 1);
+    assert_eq!(processed.summary.errors[0].line, 3);
+}
+
+#[test]
+fn check_malformed_lines_are_rejected_per_line() {
+    use crate::processors::MetricProcessor;
+    use crate::settings::Measurement;
+
+    let mut processors = Routes::default();
+    processors.insert(
+        String::from("m"),
+        MetricProcessor::new(&Measurement {
+            strip_tags: Some(vec![String::from("host")]),
+            ..Measurement::default()
+        })
+        .unwrap(),
+    );
+    processors.insert(
+        String::from("n"),
+        MetricProcessor::new(&Measurement {
+            keep_fields: Some(Vec::new()),
+            ..Measurement::default()
+        })
+        .unwrap(),
+    );
+    let chunk: Vec<Bytes> = vec![
+        Bytes::from(&b"m,host=a,\xff=b v=1i\n"[..]),
+        Bytes::from(&b"m,host=a v=1i\n"[..]),
+        Bytes::from(&b"m,host=\\ v=\"\\"[..]),
+        Bytes::from(&b"n v=1i\n"[..]),
+    ];
+    let processed = update_chunk(parse_chunk(&chunk, 1, 1, &processors, &Limits::default()));
+    assert_eq!(processed.lines, vec![Bytes::from(&b"m v=1i\n"[..])]);
+    assert_eq!(processed.summary.rejected, 2);
+    assert_eq!(processed.summary.errors[0].line, 1);
+    assert_eq!(processed.summary.errors[1].line, 3);
+    // Filtering every field away is the route's doing, not a malformed line.
+    assert_eq!(processed.summary.dropped, 1);
+}
+
+#[test]
+fn check_comments_blank_lines_and_crlf() {
+    use crate::processors::MetricProcessor;
+    use crate::settings::Measurement;
+
+    let mut processors = Routes::default();
+    processors.insert(
+        String::from("m"),
+        MetricProcessor::new(&Measurement::default()).unwrap(),
+    );
+    let chunk: Vec<Bytes> = vec![
+        Bytes::from(&b"# a comment\r\n"[..]),
+        Bytes::from(&b"\r\n"[..]),
+        Bytes::from(&b"  \n"[..]),
+        Bytes::from(&b"m v=1i\r\n"[..]),
+        Bytes::from(&b"\tm v=2i\n"[..]),
+        Bytes::from(&b"m v=3i"[..]),
+    ];
+    let processed = update_chunk(parse_chunk(&chunk, 1, 1, &processors, &Limits::default()));
+    let expected: Vec<&[u8]> = vec![b"m v=1i\n", b"m v=2i\n", b"m v=3i\n"];
+    assert_eq!(processed.lines, expected);
+    assert_eq!(processed.summary.lines, 6);
+    assert_eq!(processed.summary.rejected, 0);
+}
@@ -0,0 +1,316 @@
+use bytes::buf::BufMut;
+use bytes::{Bytes, BytesMut};
+use std::fmt::Write;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parser::{unescape_key, unescape_measurement, Pairs};
+
+/// A point borrowed straight from a line of line protocol.
+/// Every slice is raw, exactly as it appeared on the wire, escapes included.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PointRef<'a> {
+    pub measurement: &'a [u8],
+    pub tags: Pairs<'a>,
+    pub fields: Pairs<'a>,
+    pub timestamp: Option<&'a [u8]>,
+}
+
+/// A typed field value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    String(String),
+    Boolean(bool),
+}
+
+/// An owned point with unescaped names and typed field values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp: Option<i64>,
+}
+
+/// The current time as a line protocol timestamp, in nanoseconds.
+pub fn now_nanos() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as i64)
+}
+
+/// Nanoseconds per timestamp unit, for the `precision` of a write.
+pub fn precision_nanos(precision: &str) -> Option<i64> {
+    match precision {
+        "n" | "ns" => Some(1),
+        "u" | "us" => Some(1_000),
+        "ms" => Some(1_000_000),
+        "s" => Some(1_000_000_000),
+        "m" => Some(60_000_000_000),
+        "h" => Some(3_600_000_000_000),
+        _ => None,
+    }
+}
+
+fn owned_string(bytes: &[u8]) -> Option<String> {
+    str::from_utf8(bytes).ok().map(String::from)
+}
+
+fn parse_number<T: str::FromStr>(bytes: &[u8]) -> Option<T> {
+    str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+impl<'a> PointRef<'a> {
+    /// Unescapes and types every part of the point.
+    /// Returns `None` if any part is not valid UTF-8 or a field value can't be typed.
+    pub fn to_point(&self) -> Option<Point> {
+        let measurement = owned_string(&unescape_measurement(self.measurement))?;
+        let mut tags = Vec::with_capacity(self.tags.len());
+        for (key, value) in &self.tags {
+            tags.push((
+                owned_string(&unescape_key(key))?,
+                owned_string(&unescape_key(value))?,
+            ));
+        }
+        let mut fields = Vec::with_capacity(self.fields.len());
+        for (key, value) in &self.fields {
+            fields.push((
+                owned_string(&unescape_key(key))?,
+                FieldValue::parse(value).ok()?,
+            ));
+        }
+        let timestamp = match self.timestamp {
+            Some(t) => Some(parse_number(t)?),
+            None => None,
+        };
+        Some(Point {
+            measurement,
+            tags,
+            fields,
+            timestamp,
+        })
+    }
+}
+
+impl FieldValue {
+    /// Types a raw field value using the line protocol literal rules:
+    /// `1.5` float, `1i` integer, `1u` unsigned, `"..."` string, `t`/`false` etc. boolean.
+    pub fn parse(raw: &[u8]) -> Result<FieldValue, &'static str> {
+        match raw {
+            b"t" | b"T" | b"true" | b"True" | b"TRUE" => return Ok(FieldValue::Boolean(true)),
+            b"f" | b"F" | b"false" | b"False" | b"FALSE" => return Ok(FieldValue::Boolean(false)),
+            _ => {}
+        }
+        let (&last, init) = raw.split_last().ok_or("missing field value")?;
+        match last {
+            b'"' if raw.len() >= 2 && raw[0] == b'"' => {
+                let inner = &raw[1..raw.len() - 1];
+                unescape_string(inner)
+                    .map(FieldValue::String)
+                    .ok_or("invalid UTF-8 in string literal")
+            }
+            b'i' => parse_number(init)
+                .map(FieldValue::Integer)
+                .ok_or("invalid integer literal"),
+            b'u' => parse_number(init)
+                .map(FieldValue::UInteger)
+                .ok_or("invalid unsigned integer literal"),
+            _ => {
+                // Rust accepts "inf" and "NaN", InfluxDB doesn't.
+                if !raw
+                    .iter()
+                    .all(|c| c.is_ascii_digit() || b"+-.eE".contains(c))
+                {
+                    return Err("invalid field value");
+                }
+                // Out of range values parse as infinity, which can't be written back.
+                parse_number(raw)
+                    .filter(|f: &f64| f.is_finite())
+                    .map(FieldValue::Float)
+                    .ok_or("invalid float literal")
+            }
+        }
+    }
+
+    fn write_to(&self, buf: &mut BytesMut) {
+        match self {
+            FieldValue::Float(f) => {
+                let _ = write!(buf, "{}", f);
+            }
+            FieldValue::Integer(i) => {
+                let _ = write!(buf, "{}i", i);
+            }
+            FieldValue::UInteger(u) => {
+                let _ = write!(buf, "{}u", u);
+            }
+            FieldValue::String(s) => {
+                buf.put(b'"');
+                escape_into(buf, s, b"\"\\");
+                buf.put(b'"');
+            }
+            FieldValue::Boolean(b) => buf.extend_from_slice(if *b { b"true" } else { b"false" }),
+        }
+    }
+}
+
+/// Unescapes the inside of a quoted string field, where only `\"` and `\\` are escapes.
+fn unescape_string(bytes: &[u8]) -> Option<String> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\'
+            && i + 1 < bytes.len()
+            && (bytes[i + 1] == b'"' || bytes[i + 1] == b'\\')
+        {
+            out.push(bytes[i + 1]);
+            i += 2;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Writes `s`, putting a backslash before any byte in `special`. A parser
+/// takes a backslash and the byte after it together, so a backslash that
+/// would end up right before an escape, or last, can't be written and is
+/// left out. So are line breaks, which would end the line.
+fn escape_into(buf: &mut BytesMut, s: &str, special: &[u8]) {
+    // Whether the last byte written is a backslash still waiting for its pair.
+    let mut unpaired = false;
+    for &c in s.as_bytes() {
+        if c == b'\n' || c == b'\r' {
+            continue;
+        }
+        if special.contains(&c) {
+            if unpaired {
+                buf.truncate(buf.len() - 1);
+            }
+            buf.put(b'\\');
+            unpaired = false;
+        } else {
+            unpaired = c == b'\\' && !unpaired;
+        }
+        buf.put(c);
+    }
+    if unpaired {
+        buf.truncate(buf.len() - 1);
+    }
+}
+
+impl Point {
+    fn write_series_key(&self, buf: &mut BytesMut) {
+        escape_into(buf, &self.measurement, b", ");
+        for (key, value) in &self.tags {
+            buf.put(b',');
+            escape_into(buf, key, b",= ");
+            buf.put(b'=');
+            escape_into(buf, value, b",= ");
+        }
+    }
+
+    /// The measurement and tags as they appear in line protocol, which
+    /// identifies the point's series.
+    pub fn series_key(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(64);
+        self.write_series_key(&mut buf);
+        buf.freeze()
+    }
+
+    /// Serializes the point as a single line of line protocol, including the trailing newline.
+    pub fn write_to(&self, buf: &mut BytesMut) {
+        buf.reserve(64);
+        self.write_series_key(buf);
+        let mut delimit = b' ';
+        for (key, value) in &self.fields {
+            buf.put(delimit);
+            escape_into(buf, key, b",= ");
+            buf.put(b'=');
+            value.write_to(buf);
+            delimit = b',';
+        }
+        if let Some(timestamp) = self.timestamp {
+            let _ = write!(buf, " {}", timestamp);
+        }
+        buf.put(b'\n');
+    }
+
+    pub fn to_line(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(256);
+        self.write_to(&mut buf);
+        buf.freeze()
+    }
+}
+
+/// Parses a line that's known to be valid.
+#[cfg(test)]
+pub fn test_point(line: impl AsRef<[u8]>) -> Point {
+    crate::parser::parse_point(line.as_ref(), 1)
+        .unwrap()
+        .to_point()
+        .unwrap()
+}
+
+#[test]
+fn check_field_value_types() {
+    assert_eq!(FieldValue::parse(b"1.5"), Ok(FieldValue::Float(1.5)));
+    assert_eq!(FieldValue::parse(b"-3"), Ok(FieldValue::Float(-3.0)));
+    assert_eq!(FieldValue::parse(b"42i"), Ok(FieldValue::Integer(42)));
+    assert_eq!(FieldValue::parse(b"42u"), Ok(FieldValue::UInteger(42)));
+    assert_eq!(FieldValue::parse(b"T"), Ok(FieldValue::Boolean(true)));
+    assert_eq!(FieldValue::parse(b"false"), Ok(FieldValue::Boolean(false)));
+    assert_eq!(
+        FieldValue::parse(b"\"say \\\"hi\\\" \\\\o/\""),
+        Ok(FieldValue::String(String::from("say \"hi\" \\o/")))
+    );
+    assert_eq!(FieldValue::parse(b"inf"), Err("invalid field value"));
+    assert_eq!(FieldValue::parse(b"12.5i"), Err("invalid integer literal"));
+    assert_eq!(
+        FieldValue::parse(b"-1u"),
+        Err("invalid unsigned integer literal")
+    );
+    assert_eq!(FieldValue::parse(b"1.2.3"), Err("invalid float literal"));
+    assert_eq!(FieldValue::parse(b"1e999"), Err("invalid float literal"));
+    assert_eq!(FieldValue::parse(b""), Err("missing field value"));
+}
+
+#[test]
+fn check_serializer_escapes() {
+    let point = Point {
+        measurement: String::from("my requests,total"),
+        tags: vec![(String::from("host name"), String::from("web,01=a"))],
+        fields: vec![
+            (
+                String::from("msg"),
+                FieldValue::String(String::from("a, b=c \"x\"")),
+            ),
+            (String::from("count"), FieldValue::Integer(1)),
+        ],
+        timestamp: Some(1234567890),
+    };
+    assert_eq!(
+        &point.to_line()[..],
+        &b"my\\ requests\\,total,host\\ name=web\\,01\\=a msg=\"a, b=c \\\"x\\\"\",count=1i 1234567890\n"[..]
+    );
+    let point = Point {
+        measurement: String::from("dir\\"),
+        tags: vec![(String::from("path\\"), String::from("C:\\,D:\\\\"))],
+        fields: vec![(String::from("v"), FieldValue::Integer(1))],
+        timestamp: None,
+    };
+    assert_eq!(&point.to_line()[..], &b"dir,path=C:\\,D:\\\\ v=1i\n"[..]);
+    let point = Point {
+        measurement: String::from("m"),
+        tags: vec![(String::from("cat"), String::from("a\nb"))],
+        fields: vec![(
+            String::from("s"),
+            FieldValue::String(String::from("x\r\ny")),
+        )],
+        timestamp: None,
+    };
+    assert_eq!(&point.to_line()[..], &b"m,cat=ab s=\"xy\"\n"[..]);
+}
@@ -1,77 +1,1491 @@
-use bytes::buf::BufMut;
-use bytes::BytesMut;
-use std::collections::HashSet;
-use std::fmt::Write;
-use std::str;
+use log::warn;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::parser::*;
+use crate::aggregate::{Aggregate, AggregateSpec, Aggregator};
+use crate::cardinality::CardinalityLimiter;
+use crate::dedup::{Deduplicator, Policy};
+use crate::expr::{DivisionByZero, Expression};
+use crate::glob::GlobSet;
+use crate::lookup::LookupTable;
+use crate::parser::unescape_key;
+use crate::point::{now_nanos, FieldValue, Point, PointRef};
+use crate::rate::CounterRates;
+use crate::redact::Redaction;
+use crate::sample::{Sampler, SeriesPerTag};
+use crate::settings::{
+    parse_duration, Aggregation, Cardinality, Dedup, Lookup, Measurement, Rates, Redact,
+    TagTransform,
+};
+
+/// Why a route's settings can't be used.
+#[derive(Debug)]
+pub enum SettingsError {
+    Regex(regex::Error),
+    Invalid(String),
+}
+
+impl From<regex::Error> for SettingsError {
+    fn from(e: regex::Error) -> Self {
+        SettingsError::Regex(e)
+    }
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::Regex(e) => write!(f, "{}", e),
+            SettingsError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// What to do with a processed point.
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Forward,
+    Drop,
+    /// Kept back, to be forwarded later as part of another point.
+    Hold,
+}
+
+/// Removes names matching a denylist and, if there is one, names missing
+/// from an allowlist.
+pub struct NameFilter {
+    pub strip: GlobSet,
+    pub keep: Option<GlobSet>,
+}
+
+impl NameFilter {
+    fn new(strip: &Option<Vec<String>>, keep: &Option<Vec<String>>) -> NameFilter {
+        NameFilter {
+            strip: GlobSet::new(strip.as_ref().map_or(&[], |s| s.as_slice())),
+            keep: keep.as_ref().map(|k| GlobSet::new(k)),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.strip.is_empty() && self.keep.is_none()
+    }
+
+    fn keeps(&self, name: &str) -> bool {
+        self.keep.as_ref().is_none_or(|keep| keep.matches(name)) && !self.strip.matches(name)
+    }
+}
+
+/// Rewrites the values of one tag key.
+pub struct ValueTransform {
+    pub replace: Vec<(Regex, String)>,
+    pub trim: bool,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub truncate: Option<usize>,
+}
+
+impl ValueTransform {
+    fn new(settings: &TagTransform) -> Result<ValueTransform, SettingsError> {
+        let mut replace = Vec::new();
+        for r in settings.replace.iter().flatten() {
+            replace.push((Regex::new(&r.pattern)?, r.with.clone()));
+        }
+        Ok(ValueTransform {
+            replace,
+            trim: settings.trim.unwrap_or(false),
+            lowercase: settings.lowercase.unwrap_or(false),
+            uppercase: settings.uppercase.unwrap_or(false),
+            truncate: settings.truncate,
+        })
+    }
+
+    /// Replaces, trims, changes case and truncates, in that order.
+    /// Truncation never splits a character.
+    pub fn apply(&self, value: &str) -> String {
+        let mut value = value.to_string();
+        for (regex, with) in &self.replace {
+            value = regex.replace_all(&value, with.as_str()).into_owned();
+        }
+        if self.trim {
+            value = value.trim().to_string();
+        }
+        if self.lowercase {
+            value = value.to_lowercase();
+        }
+        if self.uppercase {
+            value = value.to_uppercase();
+        }
+        if let Some(limit) = self.truncate {
+            if value.len() > limit {
+                let end = (0..=limit)
+                    .rev()
+                    .find(|&i| value.is_char_boundary(i))
+                    .unwrap_or(0);
+                value.truncate(end);
+            }
+        }
+        value
+    }
+}
+
+/// Caps the distinct values of some tags.
+pub struct TagLimit {
+    pub limiter: CardinalityLimiter,
+    pub tags: GlobSet,
+    /// Replacement for values over the limit; without one the point is dropped.
+    pub sentinel: Option<String>,
+}
+
+impl TagLimit {
+    fn new(settings: &Cardinality) -> Result<TagLimit, SettingsError> {
+        let sentinel = match settings.on_overflow.as_deref() {
+            None | Some("replace") => Some(
+                settings
+                    .sentinel
+                    .clone()
+                    .unwrap_or_else(|| String::from("__overflow__")),
+            ),
+            Some("drop") => None,
+            Some(other) => {
+                return Err(SettingsError::Invalid(format!(
+                    "unknown on_overflow '{}', expected 'replace' or 'drop'",
+                    other
+                )))
+            }
+        };
+        Ok(TagLimit {
+            limiter: CardinalityLimiter::new(
+                settings.max_values,
+                settings
+                    .window
+                    .as_deref()
+                    .map_or(Ok(Duration::from_secs(3600)), parse_duration)
+                    .map_err(SettingsError::Invalid)?,
+            ),
+            tags: GlobSet::new(
+                settings
+                    .tags
+                    .as_ref()
+                    .map_or(&[String::from("*")][..], |t| t.as_slice()),
+            ),
+            sentinel,
+        })
+    }
+
+    /// Checks each limited tag, replacing values over the limit.
+    /// Returns false if the point has to be dropped instead.
+    fn apply(&self, point: &mut Point, now: Instant) -> bool {
+        for (key, value) in point.tags.iter_mut() {
+            if !self.tags.matches(key) || self.limiter.admit(&point.measurement, key, value, now) {
+                continue;
+            }
+            match &self.sentinel {
+                Some(sentinel) => *value = sentinel.clone(),
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Adds the columns of a lookup table row as tags, keyed by a tag's value.
+pub struct Enrichment {
+    pub key_tag: String,
+    pub table: LookupTable,
+}
+
+impl Enrichment {
+    fn new(settings: &Lookup) -> Result<Enrichment, SettingsError> {
+        let table = LookupTable::open(
+            &settings.file,
+            settings.key_column.as_ref().unwrap_or(&settings.key_tag),
+            settings.columns.clone(),
+            settings
+                .check
+                .as_deref()
+                .map_or(Ok(Duration::from_secs(5)), parse_duration)
+                .map_err(SettingsError::Invalid)?,
+        )
+        .map_err(|e| SettingsError::Invalid(format!("can't load lookup table {}", e)))?;
+        Ok(Enrichment {
+            key_tag: settings.key_tag.clone(),
+            table,
+        })
+    }
+
+    /// Whether enriching the point would add anything.
+    fn applies_to(&self, point: &PointRef) -> bool {
+        point
+            .tags
+            .iter()
+            .find(|(key, _)| unescaped(key) == self.key_tag)
+            .is_some_and(|(_, value)| {
+                self.table
+                    .table()
+                    .get(&unescaped(value))
+                    .is_some_and(|row| !row.is_empty())
+            })
+    }
+
+    fn apply(&self, point: &mut Point, override_tags: bool) {
+        let table = self.table.table();
+        let row = match point
+            .tags
+            .iter()
+            .find(|(key, _)| *key == self.key_tag)
+            .and_then(|(_, value)| table.get(value))
+        {
+            Some(row) => row,
+            None => return,
+        };
+        for (key, value) in row {
+            set_tag(&mut point.tags, key.clone(), value.clone(), override_tags);
+        }
+    }
+}
+
+/// Adds a tag, or replaces an existing tag's value if `override_tags` is set.
+fn set_tag(tags: &mut Vec<(String, String)>, key: String, value: String, override_tags: bool) {
+    match tags.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, v)) if override_tags => *v = value,
+        Some(_) => {}
+        None => tags.push((key, value)),
+    }
+}
+
+fn aggregates(names: &[String]) -> Result<Vec<Aggregate>, SettingsError> {
+    names
+        .iter()
+        .map(|name| Aggregate::parse(name).map_err(SettingsError::Invalid))
+        .collect()
+}
+
+fn nanos(duration: &str) -> Result<i64, SettingsError> {
+    let nanos = parse_duration(duration)
+        .map_err(SettingsError::Invalid)?
+        .as_nanos();
+    i64::try_from(nanos)
+        .map_err(|_| SettingsError::Invalid(format!("invalid duration '{}'", duration)))
+}
+
+fn aggregator(settings: &Aggregation) -> Result<Aggregator, SettingsError> {
+    let mut fields = HashMap::new();
+    for (field, names) in settings.fields.iter().flatten() {
+        fields.insert(field.clone(), aggregates(names)?);
+    }
+    let mut histograms = HashMap::new();
+    for (field, bounds) in settings.histograms.iter().flatten() {
+        let mut bounds = bounds.clone();
+        if bounds.iter().any(|b| !b.is_finite()) {
+            return Err(SettingsError::Invalid(format!(
+                "histogram bounds for {} must be finite",
+                field
+            )));
+        }
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        bounds.dedup();
+        histograms.insert(field.clone(), bounds);
+    }
+    let spec = AggregateSpec {
+        fields,
+        default: aggregates(settings.default.as_ref().map_or(&[], |d| d.as_slice()))?,
+        histograms,
+    };
+    Ok(Aggregator::new(
+        spec,
+        nanos(&settings.window)?,
+        settings
+            .grace
+            .as_ref()
+            .map_or(Ok(10 * 1_000_000_000), |grace| nanos(grace))?,
+        settings.max_windows.unwrap_or(100_000),
+    ))
+}
+
+fn condition(name: &str, text: &Option<String>) -> Result<Option<Expression>, SettingsError> {
+    match text {
+        Some(text) => Expression::parse(text)
+            .map(Some)
+            .map_err(|e| SettingsError::Invalid(format!("{}: {}", name, e))),
+        None => Ok(None),
+    }
+}
+
+/// What to do when a computed field divides by zero.
+#[derive(Clone, Copy, PartialEq)]
+pub enum OnDivideByZero {
+    Skip,
+    Zero,
+    Drop,
+}
+
+fn computed(settings: &Measurement) -> Result<Vec<(String, Expression)>, SettingsError> {
+    let mut computed = Vec::new();
+    for (field, text) in settings.compute.iter().flatten() {
+        let expression = Expression::parse(text)
+            .map_err(|e| SettingsError::Invalid(format!("compute.{}: {}", field, e)))?;
+        computed.push((field.clone(), expression));
+    }
+    computed.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(computed)
+}
+
+fn deduplicator(settings: &Measurement, dedup: &Dedup) -> Result<Deduplicator, SettingsError> {
+    let policy = match dedup.policy.as_deref() {
+        None | Some("keep_first") => Policy::KeepFirst,
+        Some("keep_last") => Policy::KeepLast,
+        Some(other) => {
+            return Err(SettingsError::Invalid(format!(
+                "unknown dedup policy '{}', expected 'keep_first' or 'keep_last'",
+                other
+            )))
+        }
+    };
+    // Held points are flushed as they are, without going through these.
+    if policy == Policy::KeepLast && (settings.rates.is_some() || settings.aggregate.is_some()) {
+        return Err(SettingsError::Invalid(String::from(
+            "keep_last dedup can't be used with rates or aggregate",
+        )));
+    }
+    let max_points = dedup.max_points.unwrap_or(100_000);
+    if max_points == 0 {
+        return Err(SettingsError::Invalid(String::from(
+            "dedup max_points must be at least 1",
+        )));
+    }
+    Ok(Deduplicator::new(policy, nanos(&dedup.window)?, max_points))
+}
+
+fn counter_rates(settings: &Rates) -> Result<CounterRates, SettingsError> {
+    if settings.fields.is_empty() {
+        return Err(SettingsError::Invalid(String::from(
+            "rates need some fields",
+        )));
+    }
+    Ok(CounterRates::new(
+        GlobSet::new(&settings.fields),
+        settings.counter_max,
+        settings.keep_counters.unwrap_or(true),
+        settings
+            .expire
+            .as_ref()
+            .map_or(Ok(3600 * 1_000_000_000), |expire| nanos(expire))?,
+    ))
+}
 
 pub struct MetricProcessor {
-    pub tags: HashSet<String>,
+    /// For a pattern route, what the measurement name must match.
+    pub pattern: Option<Regex>,
+    /// New measurement name; may refer to `pattern` captures as `$1`.
+    pub rename_to: Option<String>,
+    pub prefix: String,
+    pub suffix: String,
+    /// Tags whose values are expanded from `pattern` captures.
+    pub capture_tags: Vec<(String, String)>,
+    pub tags: NameFilter,
+    pub tag_transforms: HashMap<String, ValueTransform>,
+    pub redact_tags: HashMap<String, Redaction>,
+    pub fields: NameFilter,
+    /// Applies to string fields only, so a field never changes type.
+    pub redact_fields: HashMap<String, Redaction>,
+    pub rename_tags: HashMap<String, String>,
+    pub rename_fields: HashMap<String, String>,
+    pub add_tags: Vec<(String, String)>,
+    /// Whether `add_tags` replaces a tag the point already has.
+    pub override_tags: bool,
+    pub cardinality: Option<TagLimit>,
+    pub lookup: Option<Enrichment>,
+    /// Fields worked out from the point, sorted by name.
+    pub compute: Vec<(String, Expression)>,
+    pub divide_by_zero: OnDivideByZero,
+    pub drop_if: Option<Expression>,
+    pub keep_if: Option<Expression>,
+    pub sampler: Option<Sampler>,
+    pub series_per_tag: Option<SeriesPerTag>,
+    pub dedup: Option<Deduplicator>,
+    pub rates: Option<CounterRates>,
+    pub aggregator: Option<Aggregator>,
+    /// Whether raw points are forwarded as well as aggregates.
+    pub keep_raw: bool,
+}
+
+fn unescaped(key: &[u8]) -> String {
+    String::from_utf8_lossy(&unescape_key(key)).into_owned()
+}
+
+/// Renames keys in place. A renamed pair replaces any other pair that ends
+/// up with the same key.
+fn rename<V>(pairs: &mut Vec<(String, V)>, renames: &HashMap<String, String>) {
+    let mut renamed = Vec::with_capacity(pairs.len());
+    for (key, _) in pairs.iter_mut() {
+        let new = renames.get(key.as_str());
+        renamed.push(new.is_some());
+        if let Some(new) = new {
+            *key = new.clone();
+        }
+    }
+    let mut owners: HashMap<&str, usize> = HashMap::new();
+    for (i, (key, _)) in pairs.iter().enumerate() {
+        let owner = owners.entry(key).or_insert(i);
+        if renamed[i] || !renamed[*owner] {
+            *owner = i;
+        }
+    }
+    let owners: Vec<usize> = pairs.iter().map(|(key, _)| owners[key.as_str()]).collect();
+    let mut i = 0;
+    pairs.retain(|_| {
+        i += 1;
+        owners[i - 1] == i - 1
+    });
+}
+
+/// A map from the settings as pairs sorted by key.
+fn sorted(map: &Option<HashMap<String, String>>) -> Vec<(String, String)> {
+    let mut pairs: Vec<(String, String)> = map
+        .iter()
+        .flatten()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+/// Fills in `$1`-style references to the route pattern's captures.
+fn expand(captures: &Option<Captures>, template: &str) -> String {
+    match captures {
+        Some(captures) => {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            expanded
+        }
+        None => template.to_string(),
+    }
+}
+
+/// The processors of all routes. A measurement goes to the route named
+/// after it or, failing that, to the first pattern route it matches.
+#[derive(Default)]
+pub struct Routes {
+    names: HashMap<String, Arc<MetricProcessor>>,
+    patterns: Vec<Arc<MetricProcessor>>,
+}
+
+impl Routes {
+    pub fn insert(&mut self, name: String, processor: MetricProcessor) {
+        let processor = Arc::new(processor);
+        if processor.pattern.is_some() {
+            self.patterns.push(processor);
+        } else {
+            self.names.insert(name, processor);
+        }
+    }
+
+    pub fn flush(&self, now: i64) -> Vec<Point> {
+        self.names
+            .values()
+            .chain(&self.patterns)
+            .flat_map(|processor| processor.flush(now))
+            .collect()
+    }
+
+    pub fn get(&self, measurement: &str) -> Option<&Arc<MetricProcessor>> {
+        self.names
+            .get(measurement)
+            .or_else(|| self.patterns.iter().find(|p| p.matches(measurement)))
+    }
+}
+
+fn redaction(settings: &Redact, key: &Option<Arc<Vec<u8>>>) -> Result<Redaction, SettingsError> {
+    let mask = settings.mask.clone().unwrap_or_else(|| String::from("***"));
+    Ok(match settings.method.as_str() {
+        "hmac" => Redaction::Hmac {
+            key: key.clone().ok_or_else(|| {
+                SettingsError::Invalid(String::from("hmac redaction needs redact_key_file"))
+            })?,
+            length: settings.length,
+        },
+        "hash" => Redaction::Hash {
+            length: settings.length.unwrap_or(16),
+        },
+        "mask" => Redaction::Mask(mask),
+        "ip_prefix" => Redaction::IpPrefix {
+            v4: settings.prefix_v4.unwrap_or(24),
+            v6: settings.prefix_v6.unwrap_or(48),
+            mask,
+        },
+        other => {
+            return Err(SettingsError::Invalid(format!(
+                "unknown redaction method '{}', expected 'hmac', 'hash', 'mask' or 'ip_prefix'",
+                other
+            )))
+        }
+    })
+}
+
+fn redactions(
+    map: &Option<HashMap<String, Redact>>,
+    key: &Option<Arc<Vec<u8>>>,
+) -> Result<HashMap<String, Redaction>, SettingsError> {
+    let mut redactions = HashMap::new();
+    for (name, settings) in map.iter().flatten() {
+        redactions.insert(name.clone(), redaction(settings, key)?);
+    }
+    Ok(redactions)
+}
+
+/// Reads the HMAC key, ignoring a trailing newline.
+fn read_key(path: &str) -> Result<Arc<Vec<u8>>, SettingsError> {
+    let mut key = fs::read(path).map_err(|e| {
+        SettingsError::Invalid(format!("can't read redact_key_file {}: {}", path, e))
+    })?;
+    while key.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(SettingsError::Invalid(format!(
+            "redact_key_file {} is empty",
+            path
+        )));
+    }
+    Ok(Arc::new(key))
 }
 
 impl MetricProcessor {
-    pub fn new(strings: Vec<String>) -> MetricProcessor {
-        let mut tags: HashSet<String> = HashSet::with_capacity(strings.len());
-        for string in strings.iter() {
-            tags.insert(string.clone());
-        }
-        MetricProcessor { tags }
-    }
-
-    pub fn process(&self, name: &str, data: &[u8]) -> usize {
-        let mut buf = BytesMut::with_capacity(1024);
-        let mut src = data;
-        match buf.write_str(name) {
-            Ok(_) => {}
-            Err(_) => {
-                return 0;
-            }
-        }
-        match parse_tags(src) {
-            Some((remaining, tags)) => {
-                for (tag, value) in tags {
-                    let stag = str::from_utf8(tag).unwrap();
-                    println!("Tag: {}", stag);
-                    if !self.tags.contains(stag) {
-                        buf.put(b',');
-                        buf.extend_from_slice(tag);
-                        buf.put(b'=');
-                        buf.extend_from_slice(value);
-                    }
+    pub fn new(settings: &Measurement) -> Result<MetricProcessor, SettingsError> {
+        let pattern = match &settings.pattern {
+            Some(pattern) => Some(Regex::new(&format!("^(?:{})$", pattern))?),
+            None => None,
+        };
+        let mut tag_transforms = HashMap::new();
+        for (key, transform) in settings.tag_transforms.iter().flatten() {
+            tag_transforms.insert(key.clone(), ValueTransform::new(transform)?);
+        }
+        let key = match &settings.redact_key_file {
+            Some(path) => Some(read_key(path)?),
+            None => None,
+        };
+        Ok(MetricProcessor {
+            pattern,
+            rename_to: settings.rename_to.clone(),
+            prefix: settings.prefix.clone().unwrap_or_default(),
+            suffix: settings.suffix.clone().unwrap_or_default(),
+            capture_tags: sorted(&settings.capture_tags),
+            tags: NameFilter::new(&settings.strip_tags, &settings.keep_tags),
+            tag_transforms,
+            redact_tags: redactions(&settings.redact_tags, &key)?,
+            fields: NameFilter::new(&settings.drop_fields, &settings.keep_fields),
+            redact_fields: redactions(&settings.redact_fields, &key)?,
+            rename_tags: settings.rename_tags.clone().unwrap_or_default(),
+            rename_fields: settings.rename_fields.clone().unwrap_or_default(),
+            add_tags: sorted(&settings.add_tags),
+            override_tags: settings.override_tags.unwrap_or(false),
+            cardinality: match &settings.cardinality {
+                Some(cardinality) => Some(TagLimit::new(cardinality)?),
+                None => None,
+            },
+            lookup: match &settings.lookup {
+                Some(lookup) => Some(Enrichment::new(lookup)?),
+                None => None,
+            },
+            compute: computed(settings)?,
+            divide_by_zero: match settings.divide_by_zero.as_deref() {
+                None | Some("skip") => OnDivideByZero::Skip,
+                Some("zero") => OnDivideByZero::Zero,
+                Some("drop") => OnDivideByZero::Drop,
+                Some(other) => {
+                    return Err(SettingsError::Invalid(format!(
+                        "unknown divide_by_zero '{}', expected 'skip', 'zero' or 'drop'",
+                        other
+                    )))
+                }
+            },
+            drop_if: condition("drop_if", &settings.drop_if)?,
+            keep_if: condition("keep_if", &settings.keep_if)?,
+            sampler: match settings.sample_rate {
+                Some(rate) if !(0.0..=1.0).contains(&rate) => {
+                    return Err(SettingsError::Invalid(format!(
+                        "sample_rate {} isn't between 0 and 1",
+                        rate
+                    )))
                 }
-                src = remaining;
-            }
-            None => (),
-        }
-        match parse_fields(src) {
-            Some((remaining, fields)) => {
-                let mut delimit = b' ';
-                for (field, value) in fields {
-                    buf.put(delimit);
-                    buf.extend_from_slice(field);
-                    buf.put(b'=');
-                    buf.extend_from_slice(value);
-                    delimit = b',';
+                Some(rate) => Some(Sampler::new(
+                    rate,
+                    settings.sample_randomly.unwrap_or(false),
+                )),
+                None => None,
+            },
+            series_per_tag: match &settings.sample_by_tag {
+                Some(by_tag) => Some(SeriesPerTag::new(
+                    by_tag.tag.clone(),
+                    by_tag.series,
+                    by_tag
+                        .window
+                        .as_ref()
+                        .map_or(Ok(3600 * 1_000_000_000), |window| nanos(window))?,
+                )),
+                None => None,
+            },
+            dedup: match &settings.dedup {
+                Some(dedup) => Some(deduplicator(settings, dedup)?),
+                None => None,
+            },
+            rates: match &settings.rates {
+                Some(rates) => Some(counter_rates(rates)?),
+                None => None,
+            },
+            aggregator: match &settings.aggregate {
+                Some(aggregate) => Some(aggregator(aggregate)?),
+                None => None,
+            },
+            keep_raw: settings
+                .aggregate
+                .as_ref()
+                .is_none_or(|aggregate| aggregate.keep_raw.unwrap_or(false)),
+        })
+    }
+
+    /// Whether this pattern route handles the measurement.
+    pub fn matches(&self, measurement: &str) -> bool {
+        self.pattern
+            .as_ref()
+            .is_some_and(|pattern| pattern.is_match(measurement))
+    }
+
+    fn renames_measurement(&self) -> bool {
+        self.rename_to.is_some() || !self.prefix.is_empty() || !self.suffix.is_empty()
+    }
+
+    /// True if `process` would leave the point unchanged, so the original
+    /// line can be forwarded as-is without re-serializing it. Routes that
+    /// keep state about the points they see never pass them through.
+    pub fn passes_through(&self, point: &PointRef) -> bool {
+        self.cardinality.is_none()
+            && self.compute.is_empty()
+            && self.drop_if.is_none()
+            && self.keep_if.is_none()
+            && self.sampler.is_none()
+            && self.series_per_tag.is_none()
+            && self.dedup.is_none()
+            && self.rates.is_none()
+            && self.aggregator.is_none()
+            && !self.renames_measurement()
+            && self.capture_tags.is_empty()
+            && self.tags_pass_through(point)
+            && self.fields_pass_through(point)
+    }
+
+    fn tags_pass_through(&self, point: &PointRef) -> bool {
+        if self
+            .lookup
+            .as_ref()
+            .is_some_and(|lookup| lookup.applies_to(point))
+        {
+            return false;
+        }
+        if self.tags.is_empty()
+            && self.tag_transforms.is_empty()
+            && self.redact_tags.is_empty()
+            && self.rename_tags.is_empty()
+            && self.add_tags.is_empty()
+        {
+            return true;
+        }
+        let tags: Vec<(String, String)> = point
+            .tags
+            .iter()
+            .map(|(key, value)| (unescaped(key), unescaped(value)))
+            .collect();
+        tags.iter().all(|(key, value)| {
+            self.tags.keeps(key)
+                && !self.rename_tags.contains_key(key)
+                && !self.redact_tags.contains_key(key)
+                && self
+                    .tag_transforms
+                    .get(key)
+                    .is_none_or(|transform| transform.apply(value) == *value)
+        }) && self.add_tags.iter().all(|(key, value)| {
+            tags.iter()
+                .any(|(k, v)| k == key && (!self.override_tags || v == value))
+        })
+    }
+
+    fn fields_pass_through(&self, point: &PointRef) -> bool {
+        (self.fields.is_empty() && self.rename_fields.is_empty() && self.redact_fields.is_empty())
+            || point.fields.iter().all(|(key, _)| {
+                let key = unescaped(key);
+                self.fields.keeps(&key)
+                    && !self.rename_fields.contains_key(&key)
+                    && !self.redact_fields.contains_key(&key)
+            })
+    }
+
+    /// Applies the route's changes to the point, and the filters that only
+    /// look at the point itself. Stateful routes then need `update` too.
+    pub fn transform(&self, point: &mut Point) -> Action {
+        // Enrich first, so the key tag can still be stripped below.
+        if let Some(lookup) = &self.lookup {
+            lookup.apply(point, self.override_tags);
+        }
+        point.tags.retain_mut(|(key, value)| {
+            if !self.tags.keeps(key) {
+                return false;
+            }
+            if let Some(transform) = self.tag_transforms.get(key.as_str()) {
+                *value = transform.apply(value);
+            }
+            if let Some(redaction) = self.redact_tags.get(key.as_str()) {
+                *value = redaction.apply(value);
+            }
+            // Line protocol has no empty tag values.
+            !value.is_empty()
+        });
+        // All computed fields are worked out before any is added.
+        let mut computed = Vec::with_capacity(self.compute.len());
+        for (field, expression) in &self.compute {
+            match (expression.value(point), self.divide_by_zero) {
+                (Ok(Some(value)), _) => computed.push((field, value)),
+                (Ok(None), _) | (Err(_), OnDivideByZero::Skip) => {}
+                (Err(DivisionByZero { zero }), OnDivideByZero::Zero) => {
+                    computed.push((field, zero))
                 }
-                src = remaining;
+                (Err(_), OnDivideByZero::Drop) => return Action::Drop,
+            }
+        }
+        for (field, value) in computed {
+            match point.fields.iter_mut().find(|(key, _)| key == field) {
+                Some((_, old)) => *old = value,
+                None => point.fields.push((field.clone(), value)),
+            }
+        }
+        point.fields.retain_mut(|(key, value)| {
+            if !self.fields.keeps(key) {
+                return false;
+            }
+            if let Some(redaction) = self.redact_fields.get(key.as_str()) {
+                // Other types are redacted as text, so the field becomes a string.
+                let text = match value {
+                    FieldValue::String(s) => redaction.apply(s),
+                    FieldValue::Float(f) => redaction.apply(&f.to_string()),
+                    FieldValue::Integer(i) => redaction.apply(&i.to_string()),
+                    FieldValue::UInteger(u) => redaction.apply(&u.to_string()),
+                    FieldValue::Boolean(b) => redaction.apply(&b.to_string()),
+                };
+                *value = FieldValue::String(text);
+            }
+            true
+        });
+        // Filtering fields away is configuration, so it's no fault of the client.
+        if point.fields.is_empty() {
+            warn!(
+                "Dropped a point of {} with no fields left after filtering",
+                point.measurement
+            );
+            return Action::Drop;
+        }
+        rename(&mut point.tags, &self.rename_tags);
+        rename(&mut point.fields, &self.rename_fields);
+        let measurement = &point.measurement;
+        let captures = self
+            .pattern
+            .as_ref()
+            .and_then(|pattern| pattern.captures(measurement));
+        let mut added = Vec::with_capacity(self.capture_tags.len() + self.add_tags.len());
+        for (key, template) in &self.capture_tags {
+            let value = expand(&captures, template);
+            // An optional group that didn't match leaves nothing to tag with.
+            if !value.is_empty() {
+                added.push((key.clone(), value));
+            }
+        }
+        added.extend(self.add_tags.iter().cloned());
+        for (key, value) in added {
+            set_tag(&mut point.tags, key, value, self.override_tags);
+        }
+        if self.renames_measurement() {
+            let name = match &self.rename_to {
+                Some(template) => expand(&captures, template),
+                None => point.measurement.clone(),
+            };
+            point.measurement = format!("{}{}{}", self.prefix, name, self.suffix);
+        }
+        // InfluxDB expects tags sorted by key.
+        point.tags.sort_by(|a, b| a.0.cmp(&b.0));
+        if self.drop_if.as_ref().is_some_and(|c| c.matches(point))
+            || self.keep_if.as_ref().is_some_and(|c| !c.matches(point))
+        {
+            return Action::Drop;
+        }
+        if self.sampler.as_ref().is_some_and(|s| !s.keeps(point)) {
+            return Action::Drop;
+        }
+        Action::Forward
+    }
+
+    /// Whether the route has steps that keep state about the points they
+    /// see, so that `update` has to be called.
+    pub fn is_stateful(&self) -> bool {
+        self.cardinality.is_some()
+            || self.series_per_tag.is_some()
+            || self.dedup.is_some()
+            || self.rates.is_some()
+            || self.aggregator.is_some()
+    }
+
+    /// Runs the steps that keep state, on a point `transform` forwarded.
+    /// Their outcome depends on the order points arrive in, so the points
+    /// of a series must be passed in the order they were written.
+    pub fn update(&self, point: &mut Point) -> Action {
+        if let Some(limit) = &self.cardinality {
+            if !limit.apply(point, Instant::now()) {
+                return Action::Drop;
             }
-            None => (),
         }
-        match parse_timestamp(src) {
-            Some((_, timestamp)) => {
-                buf.put(b' ');
-                buf.extend_from_slice(timestamp);
+        if self
+            .series_per_tag
+            .as_ref()
+            .is_some_and(|s| !s.keeps(point, now_nanos()))
+        {
+            return Action::Drop;
+        }
+        if let Some(dedup) = &self.dedup {
+            match dedup.add(point, now_nanos()) {
+                Action::Forward => {}
+                action => return action,
+            }
+        }
+        if let Some(rates) = &self.rates {
+            if !rates.apply(point, now_nanos()) {
+                return Action::Drop;
             }
-            None => (),
         }
-        buf.put(b'\n');
-        match String::from_utf8(buf.to_vec()) {
-            Ok(s) => {
-                println!("Processed: '{}'", s);
+        if let Some(aggregator) = &self.aggregator {
+            if !aggregator.add(point, now_nanos()) {
+                return Action::Drop;
+            }
+            if !self.keep_raw {
+                return Action::Hold;
             }
-            Err(_) => (),
         }
-        1
+        Action::Forward
+    }
+
+    /// Takes the points held back until `now`, a timestamp in nanoseconds.
+    pub fn flush(&self, now: i64) -> Vec<Point> {
+        if let Some(rates) = &self.rates {
+            rates.expire(now);
+        }
+        if let Some(series_per_tag) = &self.series_per_tag {
+            series_per_tag.expire(now);
+        }
+        let mut points = match &self.dedup {
+            Some(dedup) => dedup.flush(now),
+            None => Vec::new(),
+        };
+        if let Some(aggregator) = &self.aggregator {
+            points.extend(aggregator.flush(now));
+        }
+        points
+    }
+}
+
+/// Processes a line, failing with "dropped" for a dropped point.
+#[cfg(test)]
+fn processed(processor: &MetricProcessor, line: &[u8]) -> Result<String, &'static str> {
+    let point = crate::parser::parse_point(line, 1).unwrap();
+    let passes_through = processor.passes_through(&point);
+    let mut point = point.to_point().unwrap();
+    let action = match processor.transform(&mut point) {
+        Action::Forward => processor.update(&mut point),
+        action => action,
+    };
+    let result = match action {
+        Action::Forward => Ok(String::from_utf8(point.to_line().to_vec()).unwrap()),
+        Action::Drop => Err("dropped"),
+        Action::Hold => Err("held"),
+    };
+    if passes_through {
+        assert_eq!(result.as_ref().map(|l| l.as_bytes()), Ok(line));
+    }
+    result
+}
+
+#[test]
+fn check_passes_through_unless_a_tag_is_stripped() {
+    use crate::parser::parse_point;
+
+    let processor = MetricProcessor::new(&Measurement {
+        strip_tags: Some(vec![String::from("product id")]),
+        ..Measurement::default()
+    })
+    .unwrap();
+    let kept = parse_point(b"products,category=toys count=1i\n", 1).unwrap();
+    assert!(processor.passes_through(&kept));
+    let stripped = parse_point(b"products,product\\ id=42 count=1i\n", 1).unwrap();
+    assert!(!processor.passes_through(&stripped));
+}
+
+#[test]
+fn check_keep_tags_allowlist() {
+    let processor = MetricProcessor::new(&Measurement {
+        keep_tags: Some(vec![String::from("host"), String::from("region_*")]),
+        strip_tags: Some(vec![String::from("region_internal")]),
+        ..Measurement::default()
+    })
+    .unwrap();
+    let allowed = "cpu,host=a,region_eu=1 v=1i\n";
+    assert_eq!(
+        processed(&processor, allowed.as_bytes()),
+        Ok(String::from(allowed))
+    );
+    assert_eq!(
+        processed(
+            &processor,
+            b"cpu,host=a,region_eu=1,region_internal=x,user_id=42 v=1i\n"
+        ),
+        Ok(String::from(allowed))
+    );
+}
+
+#[test]
+fn check_field_filters_and_renames() {
+    let mut rename_fields = HashMap::new();
+    rename_fields.insert(String::from("dur"), String::from("duration"));
+    rename_fields.insert(String::from("latency"), String::from("duration"));
+    let processor = MetricProcessor::new(&Measurement {
+        drop_fields: Some(vec![String::from("debug_*")]),
+        rename_fields: Some(rename_fields),
+        ..Measurement::default()
+    })
+    .unwrap();
+    assert_eq!(
+        processed(&processor, b"req count=1i 10\n"),
+        Ok(String::from("req count=1i 10\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"req debug_a=1,dur=2,count=3i,debug_b=4\n"),
+        Ok(String::from("req duration=2,count=3i\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"req duration=1,dur=2\n"),
+        Ok(String::from("req duration=2\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"req,debug_a=1 debug_a=1\n"),
+        Err("dropped")
+    );
+
+    let processor = MetricProcessor::new(&Measurement {
+        keep_fields: Some(vec![String::from("*_ms")]),
+        ..Measurement::default()
+    })
+    .unwrap();
+    assert_eq!(
+        processed(&processor, b"req total_ms=1,size=2\n"),
+        Ok(String::from("req total_ms=1\n"))
+    );
+}
+
+#[test]
+fn check_tag_renames_and_additions() {
+    let mut rename_tags = HashMap::new();
+    rename_tags.insert(String::from("dc"), String::from("datacenter"));
+    let mut add_tags = HashMap::new();
+    add_tags.insert(String::from("env"), String::from("prod"));
+    let mut settings = Measurement {
+        rename_tags: Some(rename_tags),
+        add_tags: Some(add_tags),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"cpu,dc=eu1,host=a v=1\n"),
+        Ok(String::from("cpu,datacenter=eu1,env=prod,host=a v=1\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"cpu,env=dev,host=a v=1\n"),
+        Ok(String::from("cpu,env=dev,host=a v=1\n"))
+    );
+
+    settings.override_tags = Some(true);
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"cpu,host=a,env=dev v=1\n"),
+        Ok(String::from("cpu,env=prod,host=a v=1\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"cpu,env=prod,host=a v=1\n"),
+        Ok(String::from("cpu,env=prod,host=a v=1\n"))
+    );
+}
+
+#[test]
+fn check_measurement_rewrites() {
+    let processor = MetricProcessor::new(&Measurement {
+        prefix: Some(String::from("team_")),
+        suffix: Some(String::from("_v2")),
+        ..Measurement::default()
+    })
+    .unwrap();
+    assert_eq!(
+        processed(&processor, b"cpu v=1\n"),
+        Ok(String::from("team_cpu_v2 v=1\n"))
+    );
+
+    let mut capture_tags = HashMap::new();
+    capture_tags.insert(String::from("app"), String::from("$1"));
+    let processor = MetricProcessor::new(&Measurement {
+        pattern: Some(String::from("app_(.+)_latency")),
+        rename_to: Some(String::from("latency")),
+        capture_tags: Some(capture_tags),
+        ..Measurement::default()
+    })
+    .unwrap();
+    assert!(processor.matches("app_checkout_latency"));
+    assert!(!processor.matches("my_app_checkout_latency"));
+    assert_eq!(
+        processed(&processor, b"app_checkout_latency,region=eu p99=12\n"),
+        Ok(String::from("latency,app=checkout,region=eu p99=12\n"))
+    );
+
+    let invalid = MetricProcessor::new(&Measurement {
+        pattern: Some(String::from("app_(")),
+        ..Measurement::default()
+    });
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn check_routes_prefer_exact_names() {
+    let mut routes = Routes::default();
+    let settings = Measurement {
+        pattern: Some(String::from("cpu.*")),
+        prefix: Some(String::from("p_")),
+        ..Measurement::default()
+    };
+    routes.insert(
+        String::from("cpu_any"),
+        MetricProcessor::new(&settings).unwrap(),
+    );
+    routes.insert(
+        String::from("cpu"),
+        MetricProcessor::new(&Measurement::default()).unwrap(),
+    );
+    assert!(routes.get("cpu").unwrap().pattern.is_none());
+    assert!(routes.get("cpu_load").unwrap().pattern.is_some());
+    assert!(routes.get("mem").is_none());
+}
+
+#[test]
+fn check_tag_value_transforms() {
+    use crate::settings::Replace;
+
+    let mut tag_transforms = HashMap::new();
+    tag_transforms.insert(
+        String::from("path"),
+        TagTransform {
+            replace: Some(vec![Replace {
+                pattern: String::from("/[0-9]+"),
+                with: String::from("/:id"),
+            }]),
+            ..TagTransform::default()
+        },
+    );
+    tag_transforms.insert(
+        String::from("agent"),
+        TagTransform {
+            trim: Some(true),
+            lowercase: Some(true),
+            truncate: Some(6),
+            ..TagTransform::default()
+        },
+    );
+    let processor = MetricProcessor::new(&Measurement {
+        tag_transforms: Some(tag_transforms),
+        ..Measurement::default()
+    })
+    .unwrap();
+    assert_eq!(
+        processed(&processor, b"http,path=/users/:id/orders v=1\n"),
+        Ok(String::from("http,path=/users/:id/orders v=1\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"http,path=/users/12345/orders/9 v=1\n"),
+        Ok(String::from("http,path=/users/:id/orders/:id v=1\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"http,agent=\\ \\ Curl/7.1 v=1\n"),
+        Ok(String::from("http,agent=curl/7 v=1\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"http,agent=\\ \\ ,host=a v=1\n"),
+        Ok(String::from("http,host=a v=1\n"))
+    );
+
+    let truncate = ValueTransform::new(&TagTransform {
+        truncate: Some(2),
+        ..TagTransform::default()
+    })
+    .unwrap();
+    assert_eq!(truncate.apply("aé"), "a");
+}
+
+#[test]
+fn check_cardinality_overflow() {
+    let mut settings = Measurement {
+        cardinality: Some(Cardinality {
+            max_values: 2,
+            window: Some(String::from("1h")),
+            tags: Some(vec![String::from("user")]),
+            ..Cardinality::default()
+        }),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    for user in &["a", "b", "a"] {
+        let line = format!("login,host=h{},user={} v=1\n", user, user);
+        assert_eq!(processed(&processor, line.as_bytes()), Ok(line.clone()));
+    }
+    assert_eq!(
+        processed(&processor, b"login,host=hc,user=c v=1\n"),
+        Ok(String::from("login,host=hc,user=__overflow__ v=1\n"))
+    );
+
+    settings.cardinality.as_mut().unwrap().on_overflow = Some(String::from("drop"));
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert!(processed(&processor, b"login,user=a v=1\n").is_ok());
+    assert!(processed(&processor, b"login,user=b v=1\n").is_ok());
+    assert_eq!(processed(&processor, b"login,user=c v=1\n"), Err("dropped"));
+
+    settings.cardinality.as_mut().unwrap().on_overflow = Some(String::from("ignore"));
+    assert!(MetricProcessor::new(&settings).is_err());
+
+    settings.cardinality.as_mut().unwrap().on_overflow = None;
+    settings.cardinality.as_mut().unwrap().window = Some(String::from("3600"));
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_redaction() {
+    let path = std::env::temp_dir().join(format!("interflux-key-{}", std::process::id()));
+    fs::write(&path, "Jefe\n").unwrap();
+    let redact = |method: &str| Redact {
+        method: String::from(method),
+        ..Redact::default()
+    };
+    let mut redact_tags = HashMap::new();
+    redact_tags.insert(String::from("client_ip"), redact("ip_prefix"));
+    redact_tags.insert(String::from("user"), redact("hmac"));
+    let mut redact_fields = HashMap::new();
+    redact_fields.insert(String::from("email"), redact("mask"));
+    redact_fields.insert(String::from("count"), redact("mask"));
+    let mut settings = Measurement {
+        redact_tags: Some(redact_tags),
+        redact_fields: Some(redact_fields),
+        redact_key_file: Some(path.to_string_lossy().into_owned()),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        processed(&processor, b"login,host=a count=1i\n"),
+        Ok(String::from("login,host=a count=\"***\"\n"))
+    );
+    let line = b"login,client_ip=10.1.2.3,user=what\\ do\\ ya\\ want\\ for\\ nothing? email=\"bob@example.com\",count=2i\n";
+    assert_eq!(
+        processed(&processor, line),
+        Ok(String::from(
+            "login,client_ip=10.1.2.0,user=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843 email=\"***\",count=\"***\"\n"
+        ))
+    );
+
+    // The key file is gone now.
+    assert!(MetricProcessor::new(&settings).is_err());
+    settings.redact_key_file = None;
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_lookup_enrichment() {
+    let path = std::env::temp_dir().join(format!("interflux-products-{}.csv", std::process::id()));
+    fs::write(&path, "product_id,category\n42,toys\n").unwrap();
+    let processor = MetricProcessor::new(&Measurement {
+        strip_tags: Some(vec![String::from("product_id")]),
+        lookup: Some(Lookup {
+            file: path.to_string_lossy().into_owned(),
+            key_tag: String::from("product_id"),
+            check: Some(String::from("30s")),
+            ..Lookup::default()
+        }),
+        ..Measurement::default()
+    })
+    .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(
+        processed(&processor, b"sales,product_id=42,store=a count=1i\n"),
+        Ok(String::from("sales,category=toys,store=a count=1i\n"))
+    );
+    assert_eq!(
+        processed(&processor, b"sales,product_id=9,store=a count=1i\n"),
+        Ok(String::from("sales,store=a count=1i\n"))
+    );
+}
+
+#[test]
+fn check_aggregation_holds_points() {
+    let mut settings = Measurement {
+        aggregate: Some(Aggregation {
+            window: String::from("10s"),
+            default: Some(vec![String::from("max")]),
+            ..Aggregation::default()
+        }),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"cpu,host=a load=1 1000000000\n"),
+        Err("held")
+    );
+    assert_eq!(
+        processed(&processor, b"cpu,host=a load=3 2000000000\n"),
+        Err("held")
+    );
+    let flushed = processor.flush(now_nanos());
+    assert_eq!(&flushed[0].to_line()[..], &b"cpu,host=a load_max=3 0\n"[..]);
+
+    settings.aggregate.as_mut().unwrap().keep_raw = Some(true);
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert!(processed(&processor, b"cpu,host=a load=1 1000000000\n").is_ok());
+
+    settings.aggregate.as_mut().unwrap().default = Some(vec![String::from("median")]);
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_counter_rates_feed_aggregation() {
+    let settings = Measurement {
+        rates: Some(Rates {
+            fields: vec![String::from("bytes")],
+            keep_counters: Some(false),
+            ..Rates::default()
+        }),
+        aggregate: Some(Aggregation {
+            window: String::from("10s"),
+            default: Some(vec![String::from("mean")]),
+            ..Aggregation::default()
+        }),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"net,if=a bytes=0i 1000000000\n"),
+        Err("dropped")
+    );
+    assert_eq!(
+        processed(&processor, b"net,if=a bytes=100i 2000000000\n"),
+        Err("held")
+    );
+    assert_eq!(
+        processed(&processor, b"net,if=a bytes=400i 4000000000\n"),
+        Err("held")
+    );
+    let flushed = processor.flush(now_nanos());
+    assert_eq!(
+        &flushed[0].to_line()[..],
+        &b"net,if=a bytes_rate_mean=125 0\n"[..]
+    );
+
+    let settings = Measurement {
+        rates: Some(Rates::default()),
+        ..Measurement::default()
+    };
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_dedup_policies() {
+    let mut settings = Measurement {
+        dedup: Some(Dedup {
+            window: String::from("1h"),
+            ..Dedup::default()
+        }),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert!(processed(&processor, b"cpu,host=a load=1 1000000000\n").is_ok());
+    assert_eq!(
+        processed(&processor, b"cpu,host=a load=2 1000000000\n"),
+        Err("dropped")
+    );
+    assert!(processed(&processor, b"cpu,host=b load=1 1000000000\n").is_ok());
+
+    settings.dedup.as_mut().unwrap().policy = Some(String::from("keep_last"));
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"cpu,host=a load=1 1000000000\n"),
+        Err("held")
+    );
+    assert_eq!(
+        processed(&processor, b"cpu,host=a load=2 1000000000\n"),
+        Err("held")
+    );
+    assert!(processor.flush(now_nanos()).is_empty());
+    let flushed = processor.flush(now_nanos() + 3600 * 1_000_000_000);
+    assert_eq!(
+        &flushed[0].to_line()[..],
+        &b"cpu,host=a load=2 1000000000\n"[..]
+    );
+
+    settings.aggregate = Some(Aggregation {
+        window: String::from("10s"),
+        ..Aggregation::default()
+    });
+    assert!(MetricProcessor::new(&settings).is_err());
+    settings.aggregate = None;
+    settings.dedup.as_mut().unwrap().policy = Some(String::from("keep_middle"));
+    assert!(MetricProcessor::new(&settings).is_err());
+    settings.dedup.as_mut().unwrap().policy = None;
+    settings.dedup.as_mut().unwrap().window = String::from("9999999999h");
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_sampling() {
+    use crate::settings::SampleByTag;
+
+    let mut settings = Measurement {
+        sample_rate: Some(0.5),
+        sample_by_tag: Some(SampleByTag {
+            tag: String::from("host"),
+            series: 1,
+            ..SampleByTag::default()
+        }),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    let kept: Vec<bool> = (0..20)
+        .map(|i| {
+            let line = format!("debug,id={} v=1\n", i);
+            processed(&processor, line.as_bytes()).is_ok()
+        })
+        .collect();
+    assert!(kept.contains(&true) && kept.contains(&false));
+    let again: Vec<bool> = (0..20)
+        .map(|i| {
+            let line = format!("debug,id={} v=2\n", i);
+            processed(&processor, line.as_bytes()).is_ok()
+        })
+        .collect();
+    assert_eq!(kept, again);
+
+    settings.sample_rate = None;
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert!(processed(&processor, b"debug,host=a,id=1 v=1\n").is_ok());
+    assert_eq!(
+        processed(&processor, b"debug,host=a,id=2 v=1\n"),
+        Err("dropped")
+    );
+    assert!(processed(&processor, b"debug,host=b,id=2 v=1\n").is_ok());
+
+    settings.sample_rate = Some(1.5);
+    assert!(MetricProcessor::new(&settings).is_err());
+}
+
+#[test]
+fn check_drop_and_keep_conditions() {
+    let mut settings = Measurement {
+        drop_if: Some(String::from("tag.env == 'dev' or field.duration > 60000")),
+        keep_if: Some(String::from("measurement =~ '^http'")),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert!(processed(&processor, b"http,env=prod duration=10i\n").is_ok());
+    assert_eq!(
+        processed(&processor, b"http,env=dev duration=10i\n"),
+        Err("dropped")
+    );
+    assert_eq!(
+        processed(&processor, b"http,env=prod duration=60001i\n"),
+        Err("dropped")
+    );
+    assert_eq!(
+        processed(&processor, b"rpc,env=prod duration=10i\n"),
+        Err("dropped")
+    );
+
+    settings.drop_if = Some(String::from("tag.env = 'dev'"));
+    match MetricProcessor::new(&settings) {
+        Err(SettingsError::Invalid(e)) => assert_eq!(e, "drop_if: unexpected '=' at column 9"),
+        _ => panic!("expected a syntax error"),
+    }
+}
+
+#[test]
+fn check_computed_fields() {
+    let mut compute = HashMap::new();
+    compute.insert(
+        String::from("duration_ms"),
+        String::from("field.duration_us / 1000"),
+    );
+    compute.insert(
+        String::from("error_ratio"),
+        String::from("field.errors / field.requests"),
+    );
+    let mut settings = Measurement {
+        compute: Some(compute),
+        drop_fields: Some(vec![String::from("duration_us")]),
+        ..Measurement::default()
+    };
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(
+            &processor,
+            b"rpc duration_us=1500i,errors=1i,requests=4i 1\n"
+        ),
+        Ok(String::from(
+            "rpc errors=1i,requests=4i,duration_ms=1.5,error_ratio=0.25 1\n"
+        ))
+    );
+    assert_eq!(
+        processed(&processor, b"rpc errors=1i,requests=0i 1\n"),
+        Ok(String::from("rpc errors=1i,requests=0i 1\n"))
+    );
+
+    settings.divide_by_zero = Some(String::from("zero"));
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"rpc errors=1i,requests=0i 1\n"),
+        Ok(String::from("rpc errors=1i,requests=0i,error_ratio=0 1\n"))
+    );
+    settings.compute.as_mut().unwrap().insert(
+        String::from("per_request"),
+        String::from("int(field.errors / field.requests)"),
+    );
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"rpc errors=1i,requests=0i 1\n"),
+        Ok(String::from(
+            "rpc errors=1i,requests=0i,error_ratio=0,per_request=0i 1\n"
+        ))
+    );
+    settings.divide_by_zero = Some(String::from("drop"));
+    let processor = MetricProcessor::new(&settings).unwrap();
+    assert_eq!(
+        processed(&processor, b"rpc errors=1i,requests=0i 1\n"),
+        Err("dropped")
+    );
+
+    settings.divide_by_zero = None;
+    settings
+        .compute
+        .as_mut()
+        .unwrap()
+        .insert(String::from("bad"), String::from("field.a +"));
+    match MetricProcessor::new(&settings) {
+        Err(SettingsError::Invalid(e)) => assert_eq!(e, "compute.bad: expected a value at the end"),
+        _ => panic!("expected a syntax error"),
     }
 }
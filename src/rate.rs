@@ -0,0 +1,197 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::glob::GlobSet;
+use crate::point::{FieldValue, Point};
+use crate::shared::lock;
+
+/// A counter reading. Integer counters are kept exact, so deltas of large
+/// 64-bit counters don't lose precision.
+#[derive(Clone, Copy)]
+enum Reading {
+    Integer(i128),
+    Float(f64),
+}
+
+impl Reading {
+    fn of(value: &FieldValue) -> Option<Reading> {
+        match value {
+            FieldValue::Integer(i) => Some(Reading::Integer(i128::from(*i))),
+            FieldValue::UInteger(u) => Some(Reading::Integer(i128::from(*u))),
+            FieldValue::Float(f) if f.is_finite() => Some(Reading::Float(*f)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Reading::Integer(i) => i as f64,
+            Reading::Float(f) => f,
+        }
+    }
+}
+
+struct Last {
+    reading: Reading,
+    timestamp: i64,
+    /// When the series was last seen, to forget it once it goes quiet.
+    seen: i64,
+}
+
+/// Turns cumulative counters into per-second rates, remembering the last
+/// reading of each counter of each series.
+pub struct CounterRates {
+    fields: GlobSet,
+    /// The largest value before a counter wraps around to zero.
+    counter_max: Option<f64>,
+    /// Whether the counter fields are kept along with their rates.
+    keep_counters: bool,
+    /// How long a series that isn't seen is remembered, in nanoseconds.
+    expire: i64,
+    last: Mutex<HashMap<(Bytes, String), Last>>,
+}
+
+impl CounterRates {
+    pub fn new(
+        fields: GlobSet,
+        counter_max: Option<f64>,
+        keep_counters: bool,
+        expire: i64,
+    ) -> CounterRates {
+        CounterRates {
+            fields,
+            counter_max,
+            keep_counters,
+            expire,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How much a counter went up. A decrease is a wraparound if the counter
+    /// was in the top half of its range, and a reset to zero otherwise.
+    fn increase(&self, last: Reading, current: Reading) -> f64 {
+        let delta = match (last, current) {
+            (Reading::Integer(last), Reading::Integer(current)) => (current - last) as f64,
+            (last, current) => current.as_f64() - last.as_f64(),
+        };
+        if delta >= 0.0 {
+            return delta;
+        }
+        match self.counter_max {
+            Some(max) if last.as_f64() > max / 2.0 => max - last.as_f64() + current.as_f64() + 1.0,
+            _ => current.as_f64(),
+        }
+    }
+
+    /// Adds a `<field>_rate` field for each counter field of the point.
+    /// Returns false if the point has counters but no rates, as there's no
+    /// rate for the first reading of a counter, nor for one that isn't newer
+    /// than the last.
+    pub fn apply(&self, point: &mut Point, now: i64) -> bool {
+        let timestamp = point.timestamp.unwrap_or(now);
+        let series = point.series_key();
+        let mut counters = 0;
+        let mut rates = Vec::new();
+        let mut last = lock(&self.last);
+        for (name, value) in &point.fields {
+            let reading = match Reading::of(value) {
+                Some(reading) if self.fields.matches(name) => reading,
+                _ => continue,
+            };
+            counters += 1;
+            let key = (series.clone(), name.clone());
+            match last.get_mut(&key) {
+                Some(previous) if timestamp > previous.timestamp => {
+                    let seconds = (timestamp - previous.timestamp) as f64 / 1e9;
+                    let rate = self.increase(previous.reading, reading) / seconds;
+                    rates.push((format!("{}_rate", name), FieldValue::Float(rate)));
+                    *previous = Last {
+                        reading,
+                        timestamp,
+                        seen: now,
+                    };
+                }
+                Some(_) => {}
+                None => {
+                    last.insert(
+                        key,
+                        Last {
+                            reading,
+                            timestamp,
+                            seen: now,
+                        },
+                    );
+                }
+            }
+        }
+        if counters > 0 && rates.is_empty() {
+            return false;
+        }
+        if !self.keep_counters {
+            point
+                .fields
+                .retain(|(name, value)| Reading::of(value).is_none() || !self.fields.matches(name));
+        }
+        point.fields.extend(rates);
+        true
+    }
+
+    /// Forgets the series not seen for a while.
+    pub fn expire(&self, now: i64) {
+        let mut last = lock(&self.last);
+        last.retain(|_, last| now - last.seen < self.expire);
+    }
+}
+
+#[test]
+fn check_rates_resets_and_wraparound() {
+    use crate::point::test_point;
+
+    let rates = CounterRates::new(GlobSet::new(&[String::from("*_total")]), None, true, 1_000);
+    let rate = |line: &str| {
+        let mut point = test_point(line);
+        if rates.apply(&mut point, 0) {
+            Some(String::from_utf8(point.to_line().to_vec()).unwrap())
+        } else {
+            None
+        }
+    };
+    assert_eq!(rate("net,if=a rx_total=100i,up=1i 0"), None);
+    assert_eq!(
+        rate("net,if=a rx_total=300i,up=1i 2000000000").unwrap(),
+        "net,if=a rx_total=300i,up=1i,rx_total_rate=100 2000000000\n"
+    );
+    // Other series are tracked apart.
+    assert_eq!(rate("net,if=b rx_total=5i 2000000000"), None);
+    // Out of order.
+    assert_eq!(rate("net,if=a rx_total=400i 1000000000"), None);
+    // A reset counts from zero.
+    assert_eq!(
+        rate("net,if=a rx_total=50i 3000000000").unwrap(),
+        "net,if=a rx_total=50i,rx_total_rate=50 3000000000\n"
+    );
+    assert_eq!(
+        rate("net,if=a up=1i 4000000000").unwrap(),
+        "net,if=a up=1i 4000000000\n"
+    );
+
+    let rates = CounterRates::new(
+        GlobSet::new(&[String::from("c")]),
+        Some(255.0),
+        false,
+        1_000,
+    );
+    let mut point = test_point(b"m c=250u 0");
+    assert!(!rates.apply(&mut point, 0));
+    let mut point = test_point(b"m c=4u 1000000000");
+    assert!(rates.apply(&mut point, 0));
+    assert_eq!(
+        point.fields,
+        vec![(String::from("c_rate"), FieldValue::Float(10.0))]
+    );
+    // Forgotten once expired, so the next reading is a first one again.
+    rates.expire(1_000);
+    let mut point = test_point(b"m c=8u 2000000000");
+    assert!(!rates.apply(&mut point, 1_000));
+}
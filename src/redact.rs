@@ -0,0 +1,105 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// A way of hiding a sensitive value before it leaves the proxy.
+pub enum Redaction {
+    /// HMAC-SHA256 of the value under a secret key, as hex, optionally cut
+    /// to `length` characters.
+    Hmac {
+        key: Arc<Vec<u8>>,
+        length: Option<usize>,
+    },
+    /// SHA-256 of the value as hex, cut to `length` characters.
+    Hash { length: usize },
+    /// A fixed replacement.
+    Mask(String),
+    /// Zeroes the host part of an IP address, keeping the first `v4` or `v6`
+    /// bits. Values that aren't IP addresses are replaced by `mask`.
+    IpPrefix { v4: u8, v6: u8, mask: String },
+}
+
+fn hex(bytes: &[u8], length: Option<usize>) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(out, "{:02x}", b);
+    }
+    if let Some(length) = length {
+        out.truncate(length);
+    }
+    out
+}
+
+fn ip_prefix(ip: IpAddr, v4: u8, v6: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip);
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(v4.min(32)))
+                .unwrap_or(0);
+            IpAddr::from((bits & mask).to_be_bytes())
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip);
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(v6.min(128)))
+                .unwrap_or(0);
+            IpAddr::from((bits & mask).to_be_bytes())
+        }
+    }
+}
+
+impl Redaction {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Redaction::Hmac { key, length } => {
+                // HMAC takes keys of any length, so this can't fail.
+                let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC key");
+                mac.input(value.as_bytes());
+                hex(&mac.result().code(), *length)
+            }
+            Redaction::Hash { length } => hex(&Sha256::digest(value.as_bytes()), Some(*length)),
+            Redaction::Mask(mask) => mask.clone(),
+            Redaction::IpPrefix { v4, v6, mask } => match value.parse() {
+                Ok(ip) => ip_prefix(ip, *v4, *v6).to_string(),
+                Err(_) => mask.clone(),
+            },
+        }
+    }
+}
+
+#[test]
+fn check_redactions() {
+    // RFC 4231 test case 2.
+    let hmac = Redaction::Hmac {
+        key: Arc::new(b"Jefe".to_vec()),
+        length: None,
+    };
+    assert_eq!(
+        hmac.apply("what do ya want for nothing?"),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+    let hash = Redaction::Hash { length: 12 };
+    assert_eq!(hash.apply("abc"), "ba7816bf8f01");
+    assert_eq!(Redaction::Mask(String::from("***")).apply("bob"), "***");
+
+    let ip = Redaction::IpPrefix {
+        v4: 24,
+        v6: 48,
+        mask: String::from("***"),
+    };
+    assert_eq!(ip.apply("192.168.10.77"), "192.168.10.0");
+    assert_eq!(
+        ip.apply("2001:db8:85a3:8d3:1319:8a2e:370:7348"),
+        "2001:db8:85a3::"
+    );
+    assert_eq!(ip.apply("bob@example.com"), "***");
+    let all = Redaction::IpPrefix {
+        v4: 0,
+        v6: 0,
+        mask: String::new(),
+    };
+    assert_eq!(all.apply("10.1.2.3"), "0.0.0.0");
+}
@@ -0,0 +1,150 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::point::Point;
+use crate::shared::lock;
+
+/// A hash of a series key that stays the same across restarts, so the same
+/// series are sampled every time: FNV-1a, with the bits mixed afterwards
+/// since FNV leaves similar keys with similar high bits.
+fn series_hash(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in key {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// A hash as a fraction between 0 and 1.
+fn fraction(hash: u64) -> f64 {
+    hash as f64 / (u64::MAX as f64 + 1.0)
+}
+
+/// Keeps a fraction of the points, either picked at random or by series,
+/// so that a series is always either kept or left out.
+pub struct Sampler {
+    rate: f64,
+    /// For random sampling: a randomly keyed hash of a counter.
+    random: Option<(RandomState, AtomicU64)>,
+}
+
+impl Sampler {
+    pub fn new(rate: f64, random: bool) -> Sampler {
+        Sampler {
+            rate,
+            random: if random {
+                Some((RandomState::new(), AtomicU64::new(0)))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn keeps(&self, point: &Point) -> bool {
+        let hash = match &self.random {
+            Some((state, counter)) => {
+                let mut hasher = state.build_hasher();
+                hasher.write_u64(counter.fetch_add(1, Ordering::Relaxed));
+                hasher.finish()
+            }
+            None => series_hash(&point.series_key()),
+        };
+        fraction(hash) < self.rate
+    }
+}
+
+/// Keeps the first `series` series seen for each value of a tag, and drops
+/// points of any others. Points without the tag are kept. A series not seen
+/// for a window is forgotten, making room for another.
+pub struct SeriesPerTag {
+    tag: String,
+    series: usize,
+    /// In nanoseconds.
+    window: i64,
+    /// When each series of each tag value was last seen, by series hash.
+    seen: Mutex<HashMap<String, HashMap<u64, i64>>>,
+}
+
+impl SeriesPerTag {
+    pub fn new(tag: String, series: usize, window: i64) -> SeriesPerTag {
+        SeriesPerTag {
+            tag,
+            series,
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn keeps(&self, point: &Point, now: i64) -> bool {
+        let value = match point.tags.iter().find(|(key, _)| *key == self.tag) {
+            Some((_, value)) => value,
+            None => return true,
+        };
+        let hash = series_hash(&point.series_key());
+        let mut seen = lock(&self.seen);
+        let series = seen.entry(value.clone()).or_default();
+        if let Some(seen) = series.get_mut(&hash) {
+            *seen = now;
+            return true;
+        }
+        if series.len() >= self.series {
+            return false;
+        }
+        series.insert(hash, now);
+        true
+    }
+
+    /// Forgets the series not seen for a window before `now`.
+    pub fn expire(&self, now: i64) {
+        let mut seen = lock(&self.seen);
+        for series in seen.values_mut() {
+            series.retain(|_, seen| now - *seen < self.window);
+        }
+        seen.retain(|_, series| !series.is_empty());
+    }
+}
+
+#[test]
+fn check_sampling_by_series() {
+    use crate::point::test_point;
+
+    let point = |i: usize| {
+        let line = format!("debug,id={} v=1", i);
+        test_point(&line)
+    };
+    let sampler = Sampler::new(0.1, false);
+    let kept: Vec<usize> = (0..10_000).filter(|&i| sampler.keeps(&point(i))).collect();
+    assert!(kept.len() > 900 && kept.len() < 1100, "kept {}", kept.len());
+    assert!(kept.iter().all(|&i| sampler.keeps(&point(i))));
+
+    let sampler = Sampler::new(0.1, true);
+    let kept = (0..10_000).filter(|_| sampler.keeps(&point(0))).count();
+    assert!(kept > 800 && kept < 1200, "kept {}", kept);
+    assert!((0..100).all(|i| !Sampler::new(0.0, false).keeps(&point(i))));
+    assert!((0..100).all(|i| Sampler::new(1.0, true).keeps(&point(i))));
+}
+
+#[test]
+fn check_series_per_tag() {
+    use crate::point::test_point;
+
+    let limit = SeriesPerTag::new(String::from("host"), 2, 100);
+    assert!(limit.keeps(&test_point(b"m,host=a,pid=1 v=1"), 0));
+    assert!(limit.keeps(&test_point(b"m,host=a,pid=2 v=1"), 0));
+    assert!(!limit.keeps(&test_point(b"m,host=a,pid=3 v=1"), 0));
+    assert!(limit.keeps(&test_point(b"m,host=a,pid=1 v=2"), 50));
+    assert!(limit.keeps(&test_point(b"m,host=b,pid=3 v=1"), 50));
+    assert!(limit.keeps(&test_point(b"m,pid=3 v=1"), 50));
+    // pid=2 went quiet and was forgotten, pid=1 wasn't.
+    limit.expire(120);
+    assert!(limit.keeps(&test_point(b"m,host=a,pid=3 v=1"), 120));
+    assert!(!limit.keeps(&test_point(b"m,host=a,pid=2 v=1"), 120));
+}
@@ -1,18 +1,269 @@
 use config::{Config, ConfigError, Environment, File};
 use serde_derive::Deserialize;
 use std::collections::HashMap;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub measurements: Option<HashMap<String, Measurement>>,
+    /// Batches the output queue holds before writers are pushed back on.
+    pub queue_size: Option<usize>,
+    /// Processed lines per batch.
+    pub batch_size: Option<usize>,
+    /// Threads parsing and processing lines; defaults to one per core.
+    pub workers: Option<usize>,
+    #[serde(default)]
+    pub limits: Limits,
 }
 
-#[derive(Debug, Deserialize)]
+/// Per-request and per-point size limits.
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Limits {
+    pub max_body_bytes: usize,
+    pub max_line_bytes: usize,
+    pub max_lines: usize,
+    pub max_tags: usize,
+    pub max_fields: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_bytes: 25 * 1024 * 1024,
+            max_line_bytes: 64 * 1024,
+            max_lines: 100_000,
+            max_tags: 200,
+            max_fields: 200,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
 pub struct Measurement {
     pub server: String,
     pub db: String,
     pub rp: Option<String>,
+    /// Makes this a pattern route: it handles every measurement whose whole
+    /// name matches this regular expression, and the route's own name is
+    /// just a label.
+    pub pattern: Option<String>,
+    /// New measurement name; `$1` etc. refer to `pattern` captures.
+    pub rename_to: Option<String>,
+    pub prefix: Option<String>,
+    pub suffix: Option<String>,
+    /// Tags set from `pattern` captures, e.g. `{ app = '$1' }`.
+    pub capture_tags: Option<HashMap<String, String>>,
+    /// Tags to remove; glob patterns such as `user_*` are allowed.
     pub strip_tags: Option<Vec<String>>,
+    /// If set, only tags matching one of these names or patterns are kept.
+    pub keep_tags: Option<Vec<String>>,
+    /// Rewrites of tag values, by tag key.
+    pub tag_transforms: Option<HashMap<String, TagTransform>>,
+    /// Tags to rename, from old key to new key, after filtering.
+    pub rename_tags: Option<HashMap<String, String>>,
+    /// Tags added to every point.
+    pub add_tags: Option<HashMap<String, String>>,
+    /// Whether `add_tags` replaces a tag the point already has; by default
+    /// the point's own value is preserved.
+    pub override_tags: Option<bool>,
+    pub cardinality: Option<Cardinality>,
+    /// Tag values to hide, by tag key.
+    pub redact_tags: Option<HashMap<String, Redact>>,
+    /// Field values to hide, by field key. Values that aren't strings are
+    /// redacted as text, so the field becomes a string field.
+    pub redact_fields: Option<HashMap<String, Redact>>,
+    /// File holding the secret key for `hmac` redaction.
+    pub redact_key_file: Option<String>,
+    /// Adds tags from a lookup table, before `strip_tags` is applied.
+    pub lookup: Option<Lookup>,
+    /// Fields worked out from others, such as
+    /// `{ duration_ms = "field.duration_us / 1000" }`, before fields are
+    /// filtered and renamed. A field is left out if there's nothing to work
+    /// it out from.
+    pub compute: Option<HashMap<String, String>>,
+    /// What a computed field dividing by zero does: `skip` the field (the
+    /// default), set it to `zero`, or `drop` the point. The zero is an
+    /// integer if the field would otherwise have been one.
+    pub divide_by_zero: Option<String>,
+    /// Drops points matching a condition such as
+    /// `tag.env == 'dev' or field.duration > 60000`. Conditions are tested
+    /// once tags, fields and the measurement name have been rewritten.
+    pub drop_if: Option<String>,
+    /// Drops points that don't match a condition.
+    pub keep_if: Option<String>,
+    /// Fraction of points to keep, between 0 and 1. Whole series are kept or
+    /// left out, picked by a hash of the series key.
+    pub sample_rate: Option<f64>,
+    /// Picks sampled points at random instead of by series.
+    pub sample_randomly: Option<bool>,
+    /// Keeps only the first few series seen for each value of a tag.
+    pub sample_by_tag: Option<SampleByTag>,
+    /// Drops copies of points seen before, such as those resent by agents.
+    pub dedup: Option<Dedup>,
+    /// Adds per-second rates of counter fields, before aggregation.
+    pub rates: Option<Rates>,
+    /// Replaces the route's points with per-window aggregates.
+    pub aggregate: Option<Aggregation>,
+    /// Fields to remove; glob patterns are allowed.
+    pub drop_fields: Option<Vec<String>>,
+    /// If set, only fields matching one of these names or patterns are kept.
+    pub keep_fields: Option<Vec<String>>,
+    /// Fields to rename, from old name to new name, after filtering.
+    pub rename_fields: Option<HashMap<String, String>>,
+}
+
+/// Caps the distinct values each tag key of a measurement may have within
+/// a sliding window.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Cardinality {
+    pub max_values: usize,
+    /// How long a value is counted after it was last seen, such as `1h`,
+    /// which is the default.
+    pub window: Option<String>,
+    /// Tag keys to limit; glob patterns are allowed. Defaults to all tags.
+    pub tags: Option<Vec<String>>,
+    /// `replace` (the default) puts `sentinel` in place of values over the
+    /// limit; `drop` drops the point.
+    pub on_overflow: Option<String>,
+    /// Defaults to `__overflow__`.
+    pub sentinel: Option<String>,
+}
+
+/// Downsampling of a route's points by series and time window.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Aggregation {
+    /// Window length, such as `10s` or `1m`.
+    pub window: String,
+    /// How long after a window ends late points are still added to it;
+    /// defaults to 10s. Points that come later still are dropped.
+    pub grace: Option<String>,
+    /// Aggregates by field, e.g. `{ duration = ['mean', 'max', 'p99'] }`.
+    /// Percentiles such as `p50` or `p99.9` are estimated to within 1%.
+    pub fields: Option<HashMap<String, Vec<String>>>,
+    /// Histogram bucket upper bounds by field, e.g. `{ duration = [10, 100] }`.
+    /// Each bucket is emitted as its own point with an `le` tag and a
+    /// cumulative `<field>_bucket` count.
+    pub histograms: Option<HashMap<String, Vec<f64>>>,
+    /// Aggregates for fields not in `fields`; without it those are left out.
+    pub default: Option<Vec<String>>,
+    /// Forward the raw points too.
+    pub keep_raw: Option<bool>,
+    /// Most series windows held at once; defaults to 100000.
+    pub max_windows: Option<usize>,
+}
+
+/// How many series to keep for each value of a tag.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct SampleByTag {
+    pub tag: String,
+    pub series: usize,
+    /// How long a series that stops is counted; defaults to `1h`.
+    pub window: Option<String>,
+}
+
+/// Catching copies of a point: one with the same series and timestamp.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Dedup {
+    /// How long after a point is first seen its copies are caught, e.g. `30s`.
+    pub window: String,
+    /// `keep_first` (the default) forwards the first copy and drops the rest.
+    /// `keep_last` holds the point back for the window, merging the fields of
+    /// later copies into it; it can't be used with `rates` or `aggregate`.
+    pub policy: Option<String>,
+    /// Most points remembered at once; defaults to 100000.
+    pub max_points: Option<usize>,
+}
+
+/// Per-second rates of cumulative counters, as `<field>_rate` fields.
+/// Points with no rate yet, such as the first of a series, are dropped.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Rates {
+    /// Counter fields; glob patterns are allowed.
+    pub fields: Vec<String>,
+    /// The largest value before counters wrap around, such as 4294967295 for
+    /// 32-bit counters. Without it, a decrease is always a reset.
+    pub counter_max: Option<f64>,
+    /// Forward the counter fields as well as their rates; defaults to true.
+    pub keep_counters: Option<bool>,
+    /// How long a series that stops is remembered; defaults to `1h`.
+    pub expire: Option<String>,
+}
+
+/// Parses durations like `500ms`, `10s`, `1m` or `1h`.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", text))?;
+    let secs = |scale: u64| {
+        number
+            .checked_mul(scale)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("invalid duration '{}'", text))
+    };
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => secs(1),
+        "m" => secs(60),
+        "h" => secs(3600),
+        _ => Err(format!(
+            "invalid duration '{}', expected a unit of ms, s, m or h",
+            text
+        )),
+    }
+}
+
+/// A CSV or JSON table to enrich points from.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Lookup {
+    pub file: String,
+    /// Tag whose value is looked up.
+    pub key_tag: String,
+    /// Column holding the keys; defaults to `key_tag`.
+    pub key_column: Option<String>,
+    /// Columns to add as tags; defaults to all of them.
+    pub columns: Option<Vec<String>>,
+    /// How often to check the file for changes, such as `30s`; defaults to `5s`.
+    pub check: Option<String>,
+}
+
+/// How to hide a sensitive value.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Redact {
+    /// `hmac`, `hash`, `mask` or `ip_prefix`.
+    pub method: String,
+    /// Hex characters of the digest to keep; `hash` keeps 16 by default.
+    pub length: Option<usize>,
+    /// Replacement for `mask`, and for `ip_prefix` values that aren't
+    /// addresses. Defaults to `***`.
+    pub mask: Option<String>,
+    /// Bits of an IPv4 address `ip_prefix` keeps; defaults to 24.
+    pub prefix_v4: Option<u8>,
+    /// Bits of an IPv6 address `ip_prefix` keeps; defaults to 48.
+    pub prefix_v6: Option<u8>,
+}
+
+/// How to rewrite the values of a tag. Steps run in the order below.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct TagTransform {
+    pub replace: Option<Vec<Replace>>,
+    pub trim: Option<bool>,
+    pub lowercase: Option<bool>,
+    pub uppercase: Option<bool>,
+    /// Longest value to keep, in bytes.
+    pub truncate: Option<usize>,
+}
+
+/// Replaces every match of a regular expression; `with` may refer to its
+/// captures as `$1`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Replace {
+    pub pattern: String,
+    pub with: String,
 }
 
 pub fn load(path: &str) -> Result<Settings, ConfigError> {
@@ -24,3 +275,15 @@ pub fn load(path: &str) -> Result<Settings, ConfigError> {
 
     config.try_into()
 }
+
+#[test]
+fn check_parse_duration() {
+    assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+    assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+    assert!(parse_duration("10").is_err());
+    assert!(parse_duration("s").is_err());
+    assert!(parse_duration("1d").is_err());
+    assert!(parse_duration("99999999999999999h").is_err());
+}
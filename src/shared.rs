@@ -0,0 +1,7 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Locks state kept across writes. A panic while the lock was held only
+/// costs the line being processed, so the state is still used after one.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+
+/// Relative accuracy of quantile estimates: within 1% of the true value.
+const RELATIVE_ACCURACY: f64 = 0.01;
+
+/// Values closer to zero than this all count as zero.
+const MIN_MAGNITUDE: f64 = 1e-9;
+
+/// A DDSketch: a quantile estimator that buckets values on a logarithmic
+/// scale, so every estimate is within `RELATIVE_ACCURACY` of a value that
+/// was actually at that rank. Memory grows with the logarithm of the range
+/// of values, not with how many there are.
+pub struct Sketch {
+    gamma_ln: f64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+    zero: u64,
+    count: u64,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        let gamma = (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY);
+        Sketch {
+            gamma_ln: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero: 0,
+            count: 0,
+        }
+    }
+}
+
+impl Sketch {
+    fn index(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.gamma_ln).ceil() as i32
+    }
+
+    /// The value a bucket stands for, minimizing the relative error.
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.gamma_ln.exp();
+        2.0 * gamma.powi(index) / (gamma + 1.0)
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        self.count += 1;
+        if value.abs() < MIN_MAGNITUDE {
+            self.zero += 1;
+        } else if value > 0.0 {
+            *self.positive.entry(self.index(value)).or_insert(0) += 1;
+        } else {
+            *self.negative.entry(self.index(-value)).or_insert(0) += 1;
+        }
+    }
+
+    /// Estimates the `q` quantile, for `q` between 0 and 1.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q.clamp(0.0, 1.0) * (self.count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (&index, &count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return Some(-self.value(index));
+            }
+        }
+        seen += self.zero;
+        if seen > rank {
+            return Some(0.0);
+        }
+        for (&index, &count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return Some(self.value(index));
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn check_quantiles_within_relative_accuracy() {
+    let mut sketch = Sketch::default();
+    assert_eq!(sketch.quantile(0.5), None);
+    for i in 1..=10_000 {
+        sketch.add(f64::from(i));
+    }
+    for &(q, expected) in &[
+        (0.0, 1.0),
+        (0.5, 5000.0),
+        (0.9, 9000.0),
+        (0.99, 9900.0),
+        (1.0, 10_000.0),
+    ] {
+        let estimate = sketch.quantile(q).unwrap();
+        assert!(
+            (estimate - expected).abs() <= expected * RELATIVE_ACCURACY + 1.0,
+            "q{} estimated {} for {}",
+            q,
+            estimate,
+            expected
+        );
+    }
+}
+
+#[test]
+fn check_quantiles_of_signed_values() {
+    let mut sketch = Sketch::default();
+    for &value in &[-100.0, -1.0, 0.0, 0.0, 1.0, 100.0, f64::NAN] {
+        sketch.add(value);
+    }
+    assert!((sketch.quantile(0.0).unwrap() + 100.0).abs() <= 1.0);
+    assert_eq!(sketch.quantile(0.5), Some(0.0));
+    assert!((sketch.quantile(1.0).unwrap() - 100.0).abs() <= 1.0);
+}